@@ -15,19 +15,12 @@ dirNav.addEventListener('click', (event) => {
             "x-partial": "true",
         },
     }).then((response) => {
-        if (response.ok) {
-            history.pushState({}, 'new page', response.url);
-            return response.text();
-        } else if (response.status == 404) {
-            history.pushState({}, 'new page', response.url);
-            return `<h1>Page not found</h1><p>${response.url}</p>`;
-        } else {
-            throw new Error(`HTTP error, status = ${response.status}`);
-        }
+        // NOTE: error responses come with their own html fragment
+        history.pushState({}, 'new page', response.url);
+        return response.text();
     }).then((body) => {
         contentView.innerHTML = body;
-        hljs.highlightAll();
-        MathJax.typeset();
+        typesetMath();
     }).catch((error) => {
         console.log(`Error: ${error.message}`);
     });
@@ -51,17 +44,10 @@ window.addEventListener("popstate", (event) => {
             "x-partial": "true",
         },
     }).then((response) => {
-        if (response.ok) {
-            return response.text();
-        } else if (response.status == 404) {
-            return `<h1>Page not found</h1><p>${response.url}</p>`;
-        } else {
-            throw new Error(`HTTP error, status = ${response.status}`);
-        }
+        return response.text();
     }).then((body) => {
         contentView.innerHTML = body;
-        hljs.highlightAll();
-        MathJax.typeset();
+        typesetMath();
     }).catch((error) => {
         console.log(`Error: ${error.message}`);
     });
@@ -0,0 +1,155 @@
+//! Content negotiation with the `Accept` header
+//!
+//! Parses media ranges with their quality values (RFC 9110 §12.5.1), and picks which of the
+//! media types a resource can be served as is preferred by the client.
+
+/// One entry of an `Accept` header, like `text/*;q=0.5`
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaRange {
+    pub main_type: String,
+    pub subtype: String,
+    pub q: f32,
+}
+
+impl MediaRange {
+    /// Whether this range covers `media_type`, which must be a `type/subtype` pair
+    pub fn matches(&self, media_type: &str) -> bool {
+        let (main_type, subtype) = media_type.split_once('/').unwrap_or((media_type, ""));
+        (self.main_type == "*" || self.main_type.eq_ignore_ascii_case(main_type))
+            && (self.subtype == "*" || self.subtype.eq_ignore_ascii_case(subtype))
+    }
+
+    /// More specific ranges take precedence: `text/html` over `text/*` over `*/*`
+    fn specificity(&self) -> u8 {
+        match (self.main_type.as_str(), self.subtype.as_str()) {
+            ("*", _) => 0,
+            (_, "*") => 1,
+            _ => 2,
+        }
+    }
+}
+
+/// Parses an `Accept` header value, skipping any malformed entries
+pub fn parse(header: &str) -> Vec<MediaRange> {
+    header.split(',')
+        .filter_map(|entry| {
+            let mut params = entry.split(';');
+            let (main_type, subtype) = params.next()?.trim().split_once('/')?;
+            if main_type.is_empty() || subtype.is_empty() {
+                return None;
+            }
+            let mut q = 1.0;
+            for param in params {
+                if let Some((name, value)) = param.split_once('=') {
+                    if name.trim().eq_ignore_ascii_case("q") {
+                        q = value.trim().parse::<f32>().ok()?.clamp(0.0, 1.0);
+                    }
+                }
+            }
+            Some(MediaRange {
+                main_type: main_type.trim().to_ascii_lowercase(),
+                subtype: subtype.trim().to_ascii_lowercase(),
+                q,
+            })
+        })
+        .collect()
+}
+
+/// The most specific range that matches `media_type`, which gives its quality
+fn best_match<'a>(ranges: &'a [MediaRange], media_type: &str) -> Option<&'a MediaRange> {
+    ranges.iter()
+        .filter(|r| r.matches(media_type))
+        .max_by_key(|r| r.specificity())
+}
+
+/// Picks the index of the best of the `available` media types
+///
+/// Without an `Accept` header, everything is acceptable and the first type is used. Ties are
+/// broken first by which type was asked for more specifically (so `application/json, */*` gives
+/// JSON), and then by the order of `available`, which should be in order of the server's
+/// preference. Returns `None` if the client accepts none of them.
+pub fn negotiate(header: Option<&str>, available: &[&str]) -> Option<usize> {
+    let ranges = match header {
+        Some(header) => parse(header),
+        None => return if available.is_empty() { None } else { Some(0) },
+    };
+    // NOTE: an `Accept` header with nothing usable in it is treated as missing
+    if ranges.is_empty() {
+        return if available.is_empty() { None } else { Some(0) };
+    }
+    let mut best: Option<(usize, f32, u8)> = None;
+    for (i, media_type) in available.iter().enumerate() {
+        let (q, specificity) = match best_match(&ranges, media_type) {
+            Some(range) if range.q > 0.0 => (range.q, range.specificity()),
+            _ => continue,
+        };
+        let better = match best {
+            Some((_, best_q, best_specificity)) => {
+                q > best_q || (q == best_q && specificity > best_specificity)
+            },
+            None => true,
+        };
+        if better {
+            best = Some((i, q, specificity));
+        }
+    }
+    best.map(|(i, _, _)| i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AVAILABLE: [&str; 3] = ["text/html", "application/json", "text/markdown"];
+
+    #[test]
+    fn parses_quality_values() {
+        let ranges = parse("text/html;q=0.5, application/json, */*;q=0.1");
+        assert_eq!(ranges.len(), 3);
+        assert_eq!(ranges[0].q, 0.5);
+        assert_eq!(ranges[1].q, 1.0);
+        assert_eq!(ranges[2].main_type, "*");
+    }
+
+    #[test]
+    fn skips_malformed_ranges() {
+        assert_eq!(parse("html, text/html;q=abc, /json, text/plain").len(), 1);
+    }
+
+    #[test]
+    fn prefers_higher_quality() {
+        assert_eq!(negotiate(Some("text/html;q=0.5, application/json"), &AVAILABLE), Some(1));
+        assert_eq!(negotiate(Some("text/markdown, text/html;q=0.9"), &AVAILABLE), Some(2));
+    }
+
+    #[test]
+    fn zero_quality_is_refused() {
+        assert_eq!(negotiate(Some("text/html;q=0, application/json"), &AVAILABLE), Some(1));
+        assert_eq!(negotiate(Some("text/html;q=0"), &AVAILABLE), None);
+        assert_eq!(negotiate(Some("*/*, text/html;q=0"), &AVAILABLE), Some(1));
+    }
+
+    #[test]
+    fn wildcards_match() {
+        assert_eq!(negotiate(Some("text/*"), &["application/json", "text/markdown"]), Some(1));
+        assert_eq!(negotiate(Some("*/*"), &AVAILABLE), Some(0));
+        assert_eq!(negotiate(Some("image/*"), &AVAILABLE), None);
+    }
+
+    #[test]
+    fn ties_use_server_order() {
+        assert_eq!(negotiate(Some("application/json, text/html"), &AVAILABLE), Some(0));
+    }
+
+    #[test]
+    fn ties_prefer_specific_ranges() {
+        assert_eq!(negotiate(Some("application/json, */*"), &AVAILABLE), Some(1));
+        assert_eq!(negotiate(Some("text/html,application/xml;q=0.9,*/*;q=0.8"), &AVAILABLE), Some(0));
+    }
+
+    #[test]
+    fn missing_header_accepts_anything() {
+        assert_eq!(negotiate(None, &AVAILABLE), Some(0));
+        assert_eq!(negotiate(Some(""), &AVAILABLE), Some(0));
+    }
+}
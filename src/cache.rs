@@ -0,0 +1,233 @@
+//! Rendered notes kept in memory
+//!
+//! Rendering is most of the work in serving a note, and a note's partial and full pages are often
+//! asked for one after the other. Notes are kept by their canonical path and the URL path they're
+//! served at (see [Key]), with a [Stamp] of what they were rendered from: the file's modification
+//! time and size, and the state of the web-root tree that wiki links were resolved against. A note
+//! whose stamp has changed is rendered again.
+//!
+//! The cache holds roughly `config.cache_size` bytes of notes, dropping the least recently used
+//! ones to stay under it. Its [CacheStats] are served at [STATS_PATH] when `config.diagnostics`
+//! is set.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::Metadata,
+    path::PathBuf,
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use serde::Serialize;
+use tracing::trace;
+
+use crate::markdown::Note;
+
+/// Where the statistics are served
+pub const STATS_PATH: &str = "/_cache";
+
+/// Bytes counted for each note on top of its text, for the rest of the entry
+const ENTRY_OVERHEAD: u64 = 256;
+
+/// Which note, as served at which URL path
+///
+/// A file reached through a symlink is the same file at another URL path, and its relative links
+/// and wiki links are made from that path, so each one is kept apart.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Key {
+    path: PathBuf,
+    url_path: String,
+}
+
+impl Key {
+    /// The note at the canonical `path`, served at `url_path`
+    pub fn new(path: PathBuf, url_path: &str) -> Self {
+        Self { path, url_path: url_path.to_string() }
+    }
+}
+
+/// What a note was rendered from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stamp {
+    modified: SystemTime,
+    len: u64,
+    tree: u64,
+}
+
+impl Stamp {
+    /// The stamp of a file, rendered with the tree fingerprinted as `tree`
+    ///
+    /// Gives `None` when the platform has no modification times, since changes couldn't be seen.
+    pub fn new(meta: &Metadata, tree: u64) -> Option<Self> {
+        Some(Self { modified: meta.modified().ok()?, len: meta.len(), tree })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Notes dropped to make room for others
+    pub evictions: u64,
+    pub entries: usize,
+    /// Estimated memory used by the notes
+    pub bytes: u64,
+    pub limit: u64,
+}
+
+pub struct RenderCache {
+    inner: Mutex<Inner>,
+    limit: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<Key, Entry>,
+    /// Keys by when they were last used, oldest first
+    used: BTreeMap<u64, Key>,
+    /// Incremented on every use
+    clock: u64,
+    bytes: u64,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+struct Entry {
+    stamp: Stamp,
+    note: Note,
+    size: u64,
+    last_used: u64,
+}
+
+impl RenderCache {
+    /// A cache of about `limit` bytes; a limit of 0 keeps nothing
+    pub fn new(limit: u64) -> Self {
+        Self { inner: Mutex::new(Inner::default()), limit }
+    }
+
+    /// The note kept for `key`, if it's still as `stamp` describes
+    pub fn get(&self, key: &Key, stamp: &Stamp) -> Option<Note> {
+        let mut inner = self.inner.lock().expect("could not lock the render cache");
+        let Some(entry) = inner.entries.get(key) else {
+            inner.misses += 1;
+            trace!(?key, "render cache miss");
+            return None;
+        };
+        if entry.stamp != *stamp {
+            inner.misses += 1;
+            trace!(?key, "render cache entry is stale");
+            inner.remove(key);
+            return None;
+        }
+        inner.hits += 1;
+        inner.clock += 1;
+        let clock = inner.clock;
+        let entry = inner.entries.get_mut(key).expect("checked above");
+        let last_used = std::mem::replace(&mut entry.last_used, clock);
+        let note = entry.note.clone();
+        let key = inner.used.remove(&last_used).expect("every entry has a last use");
+        inner.used.insert(clock, key);
+        Some(note)
+    }
+
+    /// Keeps the note for `key`, dropping the least recently used notes to make room
+    pub fn insert(&self, key: Key, stamp: Stamp, note: &Note) {
+        let size = note_size(note);
+        if size > self.limit {
+            return;
+        }
+        let mut inner = self.inner.lock().expect("could not lock the render cache");
+        inner.remove(&key);
+        while inner.bytes + size > self.limit {
+            let (_, oldest) = inner.used.pop_first().expect("the cache can't be over its limit while empty");
+            trace!(key = ?oldest, "evicted from render cache");
+            inner.remove(&oldest);
+            inner.evictions += 1;
+        }
+        inner.clock += 1;
+        let last_used = inner.clock;
+        inner.bytes += size;
+        inner.used.insert(last_used, key.clone());
+        inner.entries.insert(key, Entry { stamp, note: note.clone(), size, last_used });
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let inner = self.inner.lock().expect("could not lock the render cache");
+        CacheStats {
+            hits: inner.hits,
+            misses: inner.misses,
+            evictions: inner.evictions,
+            entries: inner.entries.len(),
+            bytes: inner.bytes,
+            limit: self.limit,
+        }
+    }
+}
+
+impl Inner {
+    fn remove(&mut self, key: &Key) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.used.remove(&entry.last_used);
+            self.bytes -= entry.size;
+        }
+    }
+}
+
+/// Rough size of a note in memory, going by its text
+fn note_size(note: &Note) -> u64 {
+    let headings: usize = note.headings.iter().map(|h| h.text.len() + h.id.len()).sum();
+    let links: usize = note.links.iter().map(|l| l.url.len() + l.title.len()).sum();
+    let meta = serde_json::to_string(&note.meta).map(|s| s.len()).unwrap_or_default();
+    // NOTE: the table of contents holds the headings a second time
+    (note.html.len() + note.source.len() + 2 * headings + links + meta) as u64 + ENTRY_OVERHEAD
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use crate::{markdown::Renderer, wikilink::WikiLinks};
+
+    fn note(text: &str) -> Note {
+        Renderer::default().render(text.to_string(), "/note.md", &WikiLinks::default())
+    }
+
+    fn stamp(len: u64) -> Stamp {
+        Stamp { modified: SystemTime::UNIX_EPOCH + Duration::from_secs(len), len, tree: 0 }
+    }
+
+    #[test]
+    fn renders_again_when_stale() {
+        let cache = RenderCache::new(1 << 20);
+        let key = Key::new(PathBuf::from("/root/a.md"), "/a.md");
+        cache.insert(key.clone(), stamp(1), &note("a"));
+        assert_eq!(cache.get(&key, &stamp(1)).map(|n| n.source), Some("a".to_string()));
+        assert!(cache.get(&Key::new(PathBuf::from("/root/a.md"), "/link.md"), &stamp(1)).is_none());
+        assert!(cache.get(&key, &stamp(2)).is_none());
+        assert!(cache.get(&key, &stamp(1)).is_none());
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries, stats.bytes), (1, 3, 0, 0));
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let size = note_size(&note("a"));
+        let cache = RenderCache::new(2 * size);
+        let [a, b, c] = ["/a.md", "/b.md", "/c.md"].map(|p| Key::new(PathBuf::from(p), p));
+        cache.insert(a.clone(), stamp(1), &note("a"));
+        cache.insert(b.clone(), stamp(1), &note("b"));
+        assert!(cache.get(&a, &stamp(1)).is_some());
+        cache.insert(c.clone(), stamp(1), &note("c"));
+        assert!(cache.get(&b, &stamp(1)).is_none());
+        assert!(cache.get(&a, &stamp(1)).is_some());
+        assert!(cache.get(&c, &stamp(1)).is_some());
+        let stats = cache.stats();
+        assert_eq!((stats.evictions, stats.entries, stats.bytes), (1, 2, 2 * size));
+
+        let empty = RenderCache::new(0);
+        empty.insert(a.clone(), stamp(1), &note("a"));
+        assert_eq!(empty.stats().entries, 0);
+    }
+}
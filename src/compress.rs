@@ -0,0 +1,218 @@
+//! Response compression
+//!
+//! Negotiates a `Content-Encoding` from the request's `Accept-Encoding`, and compresses the body
+//! of a finished response as it is streamed. Small responses and media types that are already
+//! compressed (see `Config::compress_skip`) are sent as they are.
+
+use std::io;
+
+use async_compression::tokio::bufread::{BrotliEncoder, GzipEncoder, ZstdEncoder};
+use futures::TryStreamExt;
+use hyper::{StatusCode, Body, Response, HeaderMap, http::HeaderValue, body::HttpBody};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::config::Config;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Brotli,
+    Zstd,
+}
+
+impl Encoding {
+    /// Supported encodings, in order of preference when the client has no preference
+    const PREFERRED: [Encoding; 3] = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip];
+
+    fn token(&self) -> &'static str {
+        match self {
+            Encoding::Identity => "identity",
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+        }
+    }
+}
+
+/// Picks the best encoding allowed by an `Accept-Encoding` header value
+pub fn negotiate(accept_encoding: &str) -> Encoding {
+    let mut weights: Vec<(&str, f32)> = Vec::new();
+    for item in accept_encoding.split(',') {
+        let mut params = item.split(';');
+        let coding = params.next().unwrap_or("").trim();
+        if coding.is_empty() {
+            continue;
+        }
+        let q = params
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        weights.push((coding, q));
+    }
+    let weight = |token: &str| {
+        weights.iter().find(|(c, _)| c.eq_ignore_ascii_case(token))
+            .or_else(|| weights.iter().find(|(c, _)| *c == "*"))
+            .map(|(_, q)| *q)
+            .unwrap_or(0.0)
+    };
+    let mut best = (Encoding::Identity, 0.0);
+    for encoding in Encoding::PREFERRED {
+        let q = weight(encoding.token());
+        if q > best.1 {
+            best = (encoding, q);
+        }
+    }
+    best.0
+}
+
+/// Whether a media type is worth compressing, according to the skip list
+pub fn is_compressible(content_type: &str, skip: &[String]) -> bool {
+    let essence = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    let main_type = essence.split('/').next().unwrap_or("");
+    !skip.iter().any(|pattern| match pattern.strip_suffix("/*") {
+        Some(prefix) => prefix == main_type,
+        None => *pattern == essence,
+    })
+}
+
+/// Compresses a response, if the request allows it and it is worthwhile
+///
+/// Only complete (`200 OK`) responses are compressed; partial content keeps the identity encoding
+/// so byte ranges stay meaningful. Entity tags get the encoding appended, so each encoding has its
+/// own tag. A `304 Not Modified` gets the same tag as the response it stands for (see
+/// [not_modified_etag]).
+pub fn compress(resp: Response<Body>, req_headers: &HeaderMap, config: &Config) -> Response<Body> {
+    let (mut parts, body) = resp.into_parts();
+    let compressible = parts.headers.get("Content-Type")
+        .and_then(|v| v.to_str().ok())
+        .map(|ct| is_compressible(ct, &config.compress_skip))
+        .unwrap_or(false);
+    if compressible || parts.status == StatusCode::NOT_MODIFIED {
+        parts.headers.append("Vary", HeaderValue::from_static("Accept-Encoding"));
+    }
+    if parts.status == StatusCode::NOT_MODIFIED {
+        let encoding = accepted_encoding(req_headers);
+        if let Some(etag) = parts.headers.get("ETag").and_then(|v| v.to_str().ok()) {
+            let etag = not_modified_etag(etag, req_headers, encoding);
+            parts.headers.insert("ETag", HeaderValue::from_str(&etag).unwrap());
+        }
+        return Response::from_parts(parts, body);
+    }
+    if !compressible || parts.status != StatusCode::OK || parts.headers.contains_key("Content-Encoding") {
+        return Response::from_parts(parts, body);
+    }
+    let len = parts.headers.get("Content-Length")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .or_else(|| body.size_hint().exact());
+    if len.map(|len| len < config.compress_min_size).unwrap_or(false) {
+        return Response::from_parts(parts, body);
+    }
+    let encoding = accepted_encoding(req_headers);
+    if encoding == Encoding::Identity {
+        return Response::from_parts(parts, body);
+    }
+
+    parts.headers.remove("Content-Length");
+    parts.headers.remove("Accept-Ranges");
+    parts.headers.insert("Content-Encoding", HeaderValue::from_static(encoding.token()));
+    if let Some(etag) = parts.headers.get("ETag").and_then(|v| v.to_str().ok()) {
+        let tagged = with_encoding(etag, encoding);
+        parts.headers.insert("ETag", HeaderValue::from_str(&tagged).unwrap());
+    }
+    Response::from_parts(parts, encode(body, encoding))
+}
+
+/// The encoding that the request's `Accept-Encoding` prefers
+fn accepted_encoding(req_headers: &HeaderMap) -> Encoding {
+    req_headers.get("Accept-Encoding")
+        .and_then(|v| v.to_str().ok())
+        .map(negotiate)
+        .unwrap_or(Encoding::Identity)
+}
+
+/// An entity tag with an encoding appended, like `"abc-gzip"`
+fn with_encoding(etag: &str, encoding: Encoding) -> String {
+    format!("{}-{}\"", etag.trim_end_matches('"'), encoding.token())
+}
+
+/// The tag for a `304 Not Modified`, which must be the same as the `200 OK` it revalidates
+///
+/// A 304 has no body to decide whether to compress by, but the client's `If-None-Match` holds the
+/// tag of the response it has: if that one was compressed with the negotiated encoding, so is the
+/// current one.
+fn not_modified_etag(etag: &str, req_headers: &HeaderMap, encoding: Encoding) -> String {
+    if encoding == Encoding::Identity {
+        return etag.to_string();
+    }
+    let tagged = with_encoding(etag, encoding);
+    let held = req_headers.get_all("If-None-Match").iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|tag| tag.trim().trim_start_matches("W/") == tagged.trim_start_matches("W/"));
+    if held { tagged } else { etag.to_string() }
+}
+
+fn encode(body: Body, encoding: Encoding) -> Body {
+    let reader = StreamReader::new(TryStreamExt::map_err(body, io::Error::other));
+    match encoding {
+        Encoding::Gzip => Body::wrap_stream(ReaderStream::new(GzipEncoder::new(reader))),
+        Encoding::Brotli => Body::wrap_stream(ReaderStream::new(BrotliEncoder::new(reader))),
+        Encoding::Zstd => Body::wrap_stream(ReaderStream::new(ZstdEncoder::new(reader))),
+        Encoding::Identity => Body::wrap_stream(ReaderStream::new(reader)),
+    }
+}
+
+/// Removes the encoding suffix that [compress] adds to entity tags
+pub fn strip_etag_encoding(etag: &str) -> String {
+    for encoding in Encoding::PREFERRED {
+        if let Some(stripped) = etag.strip_suffix(&format!("-{}\"", encoding.token())) {
+            return format!("{stripped}\"");
+        }
+    }
+    etag.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiates_preferred_encoding() {
+        assert_eq!(negotiate("gzip, deflate, br"), Encoding::Brotli);
+        assert_eq!(negotiate("gzip"), Encoding::Gzip);
+        assert_eq!(negotiate("gzip;q=1, br;q=0.5"), Encoding::Gzip);
+        assert_eq!(negotiate("*"), Encoding::Brotli);
+        assert_eq!(negotiate("*;q=0, gzip"), Encoding::Gzip);
+        assert_eq!(negotiate("identity"), Encoding::Identity);
+        assert_eq!(negotiate("br;q=0"), Encoding::Identity);
+    }
+
+    #[test]
+    fn skips_listed_types() {
+        let skip = vec!["image/png".to_string(), "video/*".to_string()];
+        assert!(is_compressible("text/html; charset=utf-8", &skip));
+        assert!(is_compressible("image/svg+xml", &skip));
+        assert!(!is_compressible("image/png", &skip));
+        assert!(!is_compressible("video/mp4", &skip));
+    }
+
+    #[test]
+    fn tags_not_modified_like_the_response() {
+        let mut headers = HeaderMap::new();
+        headers.insert("If-None-Match", HeaderValue::from_static("\"x\", \"abc-gzip\""));
+        assert_eq!(not_modified_etag("\"abc\"", &headers, Encoding::Gzip), "\"abc-gzip\"");
+        assert_eq!(not_modified_etag("\"abc\"", &headers, Encoding::Brotli), "\"abc\"");
+        assert_eq!(not_modified_etag("\"abc\"", &headers, Encoding::Identity), "\"abc\"");
+        headers.insert("If-None-Match", HeaderValue::from_static("\"abc\""));
+        assert_eq!(not_modified_etag("\"abc\"", &headers, Encoding::Gzip), "\"abc\"");
+    }
+
+    #[test]
+    fn strips_encoding_from_etags() {
+        assert_eq!(strip_etag_encoding("\"abc-gzip\""), "\"abc\"");
+        assert_eq!(strip_etag_encoding("W/\"abc-br\""), "W/\"abc\"");
+        assert_eq!(strip_etag_encoding("\"abc\""), "\"abc\"");
+    }
+}
@@ -0,0 +1,235 @@
+//! Conditional requests
+//!
+//! Validators (`ETag` and `Last-Modified`) for files and rendered pages, and the checks for
+//! `If-None-Match` / `If-Modified-Since` that allow answering with `304 Not Modified`.
+//!
+//! Entity tags are built from file metadata, so they change whenever a file is written. Rendered
+//! pages mix in whatever else goes into the page (templates, the directory tree) with
+//! [Validators::derive].
+
+use std::{
+    fs::Metadata,
+    future::Future,
+    path::Path,
+    hash::{Hash, Hasher},
+    collections::hash_map::DefaultHasher,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use hyper::{StatusCode, Body, Response, HeaderMap, http::HeaderValue};
+
+use crate::{compress::strip_etag_encoding, error::Result};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Validators {
+    /// Strong entity tag, including the surrounding quotes
+    pub etag: String,
+    pub last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    pub fn from_metadata(metadata: &Metadata) -> Self {
+        let modified = metadata.modified().ok();
+        let nanos = modified
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        Self {
+            etag: format!("\"{:x}-{:x}\"", metadata.len(), nanos),
+            last_modified: modified,
+        }
+    }
+
+    pub async fn for_file(path: &Path) -> std::io::Result<Self> {
+        let metadata = tokio::fs::metadata(path).await?;
+        Ok(Self::from_metadata(&metadata))
+    }
+
+    /// Validators for content generated from the one these were made from
+    ///
+    /// The `variant` separates different representations of the same file, and `state` holds
+    /// fingerprints of anything else the representation depends on.
+    pub fn derive(&self, variant: &str, state: &[u64]) -> Self {
+        let mut hasher = DefaultHasher::new();
+        self.etag.hash(&mut hasher);
+        variant.hash(&mut hasher);
+        state.hash(&mut hasher);
+        Self {
+            etag: format!("\"{:016x}\"", hasher.finish()),
+            last_modified: self.last_modified,
+        }
+    }
+
+    /// Validators for content with no backing file, from a fingerprint of it
+    pub fn from_fingerprint(variant: &str, state: &[u64]) -> Self {
+        let mut hasher = DefaultHasher::new();
+        variant.hash(&mut hasher);
+        state.hash(&mut hasher);
+        Self {
+            etag: format!("\"{:016x}\"", hasher.finish()),
+            last_modified: None,
+        }
+    }
+
+    /// Drop `Last-Modified`, for content which can change without the file changing
+    pub fn without_last_modified(mut self) -> Self {
+        self.last_modified = None;
+        self
+    }
+
+    /// Whether the request's preconditions mean a `304 Not Modified` should be sent
+    ///
+    /// `If-None-Match` takes precedence, and `If-Modified-Since` is only checked without it.
+    pub fn not_modified(&self, headers: &HeaderMap) -> bool {
+        if let Some(value) = headers.get("If-None-Match") {
+            return value.to_str()
+                .map(|v| etag_list_matches(v, &self.etag))
+                .unwrap_or(false);
+        }
+        if let (Some(value), Some(modified)) = (headers.get("If-Modified-Since"), self.last_modified) {
+            return value.to_str().ok()
+                .and_then(|v| httpdate::parse_http_date(v).ok())
+                .map(|since| truncate(modified) <= since)
+                .unwrap_or(false);
+        }
+        false
+    }
+
+    /// Whether an `If-Range` value still refers to this representation
+    ///
+    /// Uses the strong comparison required by RFC 9110 §13.1.5.
+    pub fn if_range_matches(&self, value: &str) -> bool {
+        let value = value.trim();
+        if value.starts_with('"') {
+            return value == self.etag;
+        }
+        match (httpdate::parse_http_date(value), self.last_modified) {
+            (Ok(date), Some(modified)) => truncate(modified) == date,
+            _ => false,
+        }
+    }
+
+    /// Adds `ETag` and `Last-Modified` to a response
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        if let Ok(etag) = HeaderValue::from_str(&self.etag) {
+            headers.insert("ETag", etag);
+        }
+        if let Some(modified) = self.last_modified {
+            headers.insert("Last-Modified", HeaderValue::from_str(&httpdate::fmt_http_date(modified)).unwrap());
+        }
+    }
+}
+
+/// Answer with `304 Not Modified` if the request allows it, or otherwise await `render`
+///
+/// The validators are only added to successful responses, and not to ones that `render` marked
+/// `Cache-Control: no-store`, which can't be revalidated.
+pub async fn respond<F>(headers: &HeaderMap, validators: &Validators, render: F) -> Result<Response<Body>>
+    where F: Future<Output = Result<Response<Body>>>
+{
+    if validators.not_modified(headers) {
+        return Ok(not_modified(validators));
+    }
+    let mut resp = render.await?;
+    let no_store = resp.headers().get("Cache-Control").is_some_and(|v| v == "no-store");
+    if resp.status().is_success() && !no_store {
+        validators.apply(resp.headers_mut());
+    }
+    Ok(resp)
+}
+
+pub fn not_modified(validators: &Validators) -> Response<Body> {
+    let mut resp = Response::builder()
+        .status(StatusCode::NOT_MODIFIED)
+        .body(Body::empty())
+        .unwrap();
+    validators.apply(resp.headers_mut());
+    resp
+}
+
+/// Hash of anything, for fingerprinting state that goes into rendered pages
+pub fn fingerprint<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Weak comparison against a list of entity tags, as used by `If-None-Match`
+///
+/// Tags of compressed responses match the uncompressed one, since they are the same content.
+fn etag_list_matches(list: &str, etag: &str) -> bool {
+    let opaque = etag.trim_start_matches("W/");
+    list.split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || strip_etag_encoding(tag).trim_start_matches("W/") == opaque)
+}
+
+/// HTTP dates only have a resolution of seconds
+fn truncate(time: SystemTime) -> SystemTime {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => UNIX_EPOCH + std::time::Duration::from_secs(d.as_secs()),
+        Err(_) => time,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn validators() -> Validators {
+        Validators {
+            etag: "\"abc\"".to_string(),
+            last_modified: Some(UNIX_EPOCH + Duration::from_millis(1_000_000_500)),
+        }
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (k, v) in pairs {
+            map.insert(*k, HeaderValue::from_str(v).unwrap());
+        }
+        map
+    }
+
+    #[test]
+    fn matches_if_none_match() {
+        let v = validators();
+        assert!(v.not_modified(&headers(&[("If-None-Match", "\"abc\"")])));
+        assert!(v.not_modified(&headers(&[("If-None-Match", "\"x\", W/\"abc\"")])));
+        assert!(v.not_modified(&headers(&[("If-None-Match", "*")])));
+        assert!(v.not_modified(&headers(&[("If-None-Match", "\"abc-gzip\"")])));
+        assert!(!v.not_modified(&headers(&[("If-None-Match", "\"abd\"")])));
+    }
+
+    #[test]
+    fn if_none_match_takes_precedence() {
+        let v = validators();
+        let date = httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(2_000_000_000));
+        assert!(!v.not_modified(&headers(&[("If-None-Match", "\"x\""), ("If-Modified-Since", &date)])));
+    }
+
+    #[test]
+    fn matches_if_modified_since() {
+        let v = validators();
+        let same = httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(1_000_000));
+        let before = httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(999_999));
+        assert!(v.not_modified(&headers(&[("If-Modified-Since", &same)])));
+        assert!(!v.not_modified(&headers(&[("If-Modified-Since", &before)])));
+    }
+
+    #[test]
+    fn if_range_is_strong() {
+        let v = validators();
+        assert!(v.if_range_matches("\"abc\""));
+        assert!(!v.if_range_matches("W/\"abc\""));
+        assert!(v.if_range_matches(&httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(1_000_000))));
+    }
+
+    #[test]
+    fn derived_tags_differ_by_variant() {
+        let v = validators();
+        assert_ne!(v.derive("partial", &[]).etag, v.derive("full", &[]).etag);
+        assert_ne!(v.derive("full", &[1]).etag, v.derive("full", &[2]).etag);
+    }
+}
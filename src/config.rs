@@ -3,16 +3,63 @@
 
 use std::{
     env,
+    fmt,
+    str::FromStr,
     path::{Path, PathBuf}, 
     net::{SocketAddr, IpAddr},
 };
 
+use tracing::{debug, warn};
+
+use crate::filter::PathFilter;
+use crate::highlight;
+use crate::math::{self, Delimiters, MathMode};
+use crate::markdown::Extensions;
+use crate::logging::AccessLogFormat;
+
 const ROOTDIR_KEY: &str = "WEB_ROOT";
 const STATICDIR_KEY: &str = "STATIC_DIR";
 const TEMPLATEDIR_KEY: &str = "TEMPLATE_DIR";
+const COMPRESS_MIN_KEY: &str = "COMPRESS_MIN_SIZE";
+const COMPRESS_SKIP_KEY: &str = "COMPRESS_SKIP";
+const SYMLINKS_KEY: &str = "SYMLINKS";
+const DENY_KEY: &str = "DENY_PATHS";
+const ALLOW_KEY: &str = "ALLOW_PATHS";
+const ACCESS_LOG_KEY: &str = "ACCESS_LOG";
+const TLS_CERT_KEY: &str = "TLS_CERT";
+const TLS_KEY_KEY: &str = "TLS_KEY";
+const REDIRECT_PORT_KEY: &str = "HTTP_REDIRECT_PORT";
+const HIGHLIGHT_THEME_KEY: &str = "HIGHLIGHT_THEME";
+const SYNTAX_DIR_KEY: &str = "SYNTAX_DIR";
+const MATH_INLINE_KEY: &str = "MATH_INLINE";
+const MATH_DISPLAY_KEY: &str = "MATH_DISPLAY";
+const MATH_MODE_KEY: &str = "MATH_MODE";
+const MATH_MACROS_KEY: &str = "MATH_MACROS";
+const CACHE_SIZE_KEY: &str = "CACHE_SIZE";
+const EXTENSIONS_KEY: &str = "MARKDOWN_EXTENSIONS";
+const RENDERERS_KEY: &str = "RENDERERS";
+const RENDERER_TIMEOUT_KEY: &str = "RENDERER_TIMEOUT";
+const DIAGNOSTICS_KEY: &str = "DIAGNOSTICS";
 
 const DEFAULT_ADDR: ([u8; 4], u16)  = ([0,0,0,0], 7878);
 
+/// Responses smaller than this are not worth compressing
+const DEFAULT_COMPRESS_MIN: u64 = 1024;
+/// Media types which are already compressed
+const DEFAULT_COMPRESS_SKIP: [&str; 16] = [
+    "image/png", "image/jpeg", "image/gif", "image/webp", "image/avif",
+    "video/*", "audio/*",
+    "application/zip", "application/gzip", "application/zstd", "application/x-bzip2",
+    "application/x-xz", "application/x-7z-compressed", "application/pdf",
+    "font/woff", "font/woff2",
+];
+/// Seconds that an external renderer may run for
+const DEFAULT_RENDERER_TIMEOUT: u64 = 10;
+/// Rendered notes kept in memory, in bytes
+const DEFAULT_CACHE_SIZE: u64 = 32 * 1024 * 1024;
+/// Paths that are never served: all dotfiles, like `.git` and `.env`
+const DEFAULT_DENY: [&str; 1] = [".*"];
+
 /// The config object to handle how pages are served
 ///
 /// # Properties
@@ -20,12 +67,47 @@ const DEFAULT_ADDR: ([u8; 4], u16)  = ([0,0,0,0], 7878);
 /// - `staticdir` the directory that holds all "static" files
 /// - `header` the file name (relative to `staticdir`) of the header to prepend to all md files
 /// - `footer` the file name (relative to `staticdir`) of the footer to append to all md files
-#[derive(Debug, PartialEq, Eq)]
+/// - `compress_min_size` the smallest response body (in bytes) that will be compressed
+/// - `compress_skip` media types that are never compressed, either exact or as `type/*`
+/// - `symlinks` which symbolic links may be followed when serving files
+/// - `filter` patterns of paths which are never served or listed (see [PathFilter])
+/// - `access_log` the format of the access log written to stdout, if any
+/// - `tls` the certificate and key to serve HTTPS with, if any
+/// - `redirect_port` a port to listen for plain HTTP on, redirecting to HTTPS
+/// - `highlight_theme` the theme for highlighting code, or `none` to leave code alone
+/// - `syntax_dir` a directory of extra `.sublime-syntax` definitions
+/// - `math_inline` and `math_display` the delimiters of LaTeX math, which is passed through unchanged
+/// - `math_mode` whether math is typeset by the client, or rendered to MathML by the server
+/// - `math_macros` a TOML file of LaTeX macros, for rendering MathML
+/// - `extensions` the markdown extensions, which notes can change in their metadata
+/// - `renderers` a TOML file of commands that render code blocks, by language
+/// - `renderer_timeout` how many seconds those commands may run for
+/// - `cache_size` roughly how many bytes of rendered notes to keep in memory, or 0 for none
+/// - `diagnostics` whether the broken-link report and the cache's statistics are served
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Config {
     pub rootdir: PathBuf,
     pub staticdir: PathBuf,
     pub template_dir: PathBuf,
     pub addr: SocketAddr,
+    pub compress_min_size: u64,
+    pub compress_skip: Vec<String>,
+    pub symlinks: SymlinkPolicy,
+    pub filter: PathFilter,
+    pub access_log: Option<AccessLogFormat>,
+    pub tls: Option<TlsConfig>,
+    pub redirect_port: Option<u16>,
+    pub highlight_theme: String,
+    pub syntax_dir: Option<PathBuf>,
+    pub math_inline: Delimiters,
+    pub math_display: Delimiters,
+    pub math_mode: MathMode,
+    pub math_macros: Option<PathBuf>,
+    pub extensions: Extensions,
+    pub renderers: Option<PathBuf>,
+    pub renderer_timeout: u64,
+    pub cache_size: u64,
+    pub diagnostics: bool,
 }
 
 impl Config {
@@ -37,11 +119,71 @@ impl Config {
 
 impl Default for Config {
     fn default() -> Config {
+        let deny: Vec<String> = DEFAULT_DENY.iter().map(|s| s.to_string()).collect();
         Config {
             addr: SocketAddr::from(DEFAULT_ADDR),
             rootdir: PathBuf::from("./"),
             staticdir: PathBuf::from("./sample/static"),
             template_dir: PathBuf::from("./sample/templates"),
+            filter: PathFilter::new(&deny, &[]),
+            compress_min_size: DEFAULT_COMPRESS_MIN,
+            compress_skip: DEFAULT_COMPRESS_SKIP.iter().map(|s| s.to_string()).collect(),
+            symlinks: SymlinkPolicy::WithinRoot,
+            access_log: None,
+            tls: None,
+            redirect_port: None,
+            highlight_theme: highlight::DEFAULT_THEME.to_string(),
+            syntax_dir: None,
+            math_inline: math::DEFAULT_INLINE.parse().expect("the default delimiters are valid"),
+            math_display: math::DEFAULT_DISPLAY.parse().expect("the default delimiters are valid"),
+            math_mode: MathMode::Client,
+            math_macros: None,
+            extensions: Extensions::default(),
+            renderers: None,
+            renderer_timeout: DEFAULT_RENDERER_TIMEOUT,
+            cache_size: DEFAULT_CACHE_SIZE,
+            diagnostics: false,
+        }
+    }
+}
+
+/// PEM files of the certificate chain and private key for HTTPS
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// Which symbolic links may be followed when serving a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Never serve a path that goes through a symbolic link
+    Deny,
+    /// Follow links, as long as the target is inside the directory being served
+    WithinRoot,
+    /// Follow all links, wherever they go
+    All,
+}
+
+impl FromStr for SymlinkPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "deny" => Ok(SymlinkPolicy::Deny),
+            "within-root" => Ok(SymlinkPolicy::WithinRoot),
+            "all" => Ok(SymlinkPolicy::All),
+            _ => Err(format!("unknown symlink policy '{s}' (expected deny, within-root or all)")),
+        }
+    }
+}
+
+impl fmt::Display for SymlinkPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SymlinkPolicy::Deny => write!(f, "deny"),
+            SymlinkPolicy::WithinRoot => write!(f, "within-root"),
+            SymlinkPolicy::All => write!(f, "all"),
         }
     }
 }
@@ -56,6 +198,32 @@ pub struct ConfigBuilder {
     staticdir: PathBuf,
     template_dir: PathBuf,
     addr: SocketAddr,
+    compress_min_size: u64,
+    compress_skip: Vec<String>,
+    symlinks: SymlinkPolicy,
+    deny: Vec<String>,
+    allow: Vec<String>,
+    access_log: Option<AccessLogFormat>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    redirect_port: Option<u16>,
+    highlight_theme: String,
+    syntax_dir: Option<PathBuf>,
+    math_inline: Delimiters,
+    math_display: Delimiters,
+    math_mode: MathMode,
+    math_macros: Option<PathBuf>,
+    extensions: Extensions,
+    renderers: Option<PathBuf>,
+    renderer_timeout: u64,
+    cache_size: u64,
+    diagnostics: bool,
+}
+
+impl Default for ConfigBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ConfigBuilder {
@@ -67,16 +235,62 @@ impl ConfigBuilder {
             staticdir: config.staticdir,
             template_dir: config.template_dir,
             addr: config.addr,
+            compress_min_size: config.compress_min_size,
+            compress_skip: config.compress_skip,
+            symlinks: config.symlinks,
+            deny: config.filter.deny_patterns().to_vec(),
+            allow: config.filter.allow_patterns().to_vec(),
+            access_log: config.access_log,
+            tls_cert: None,
+            tls_key: None,
+            redirect_port: config.redirect_port,
+            highlight_theme: config.highlight_theme,
+            syntax_dir: config.syntax_dir,
+            math_inline: config.math_inline,
+            math_display: config.math_display,
+            math_mode: config.math_mode,
+            math_macros: config.math_macros,
+            extensions: config.extensions,
+            renderers: config.renderers,
+            renderer_timeout: config.renderer_timeout,
+            cache_size: config.cache_size,
+            diagnostics: config.diagnostics,
         }
     }
     
     /// Returns the finished Config
     pub fn build(self) -> Config {
+        let tls = match (self.tls_cert, self.tls_key) {
+            (Some(cert), Some(key)) => Some(TlsConfig { cert, key }),
+            (None, None) => None,
+            _ => {
+                warn!("TLS needs both a certificate and a key; serving plain HTTP");
+                None
+            },
+        };
         Config {
+            filter: PathFilter::new(&self.deny, &self.allow),
             rootdir: self.rootdir,
             staticdir: self.staticdir,
             template_dir: self.template_dir,
             addr: self.addr,
+            compress_min_size: self.compress_min_size,
+            compress_skip: self.compress_skip,
+            symlinks: self.symlinks,
+            access_log: self.access_log,
+            tls,
+            redirect_port: self.redirect_port,
+            highlight_theme: self.highlight_theme,
+            syntax_dir: self.syntax_dir,
+            math_inline: self.math_inline,
+            math_display: self.math_display,
+            math_mode: self.math_mode,
+            math_macros: self.math_macros,
+            extensions: self.extensions,
+            renderers: self.renderers,
+            renderer_timeout: self.renderer_timeout,
+            cache_size: self.cache_size,
+            diagnostics: self.diagnostics,
         }
     }
 
@@ -84,19 +298,132 @@ impl ConfigBuilder {
     ///
     /// rootdir sourced rom "WEB_ROOT"
     /// staticdir sourced from "STATIC_DIR"
+    /// template_dir sourced from "TEMPLATE_DIR"
+    /// compress_min_size sourced from "COMPRESS_MIN_SIZE"
+    /// compress_skip sourced from "COMPRESS_SKIP", as a comma-separated list
+    /// symlinks sourced from "SYMLINKS" (deny, within-root or all)
+    /// denied and allowed paths sourced from "DENY_PATHS" and "ALLOW_PATHS", as comma-separated
+    /// lists of patterns
+    /// access_log sourced from "ACCESS_LOG" (common or combined)
+    /// tls sourced from "TLS_CERT" and "TLS_KEY", which must both be set
+    /// redirect_port sourced from "HTTP_REDIRECT_PORT"
+    /// highlight_theme sourced from "HIGHLIGHT_THEME"
+    /// syntax_dir sourced from "SYNTAX_DIR"
+    /// math_inline and math_display sourced from "MATH_INLINE" and "MATH_DISPLAY", as
+    /// comma-separated lists of delimiters (or `none`)
+    /// math_mode sourced from "MATH_MODE" (client or mathml)
+    /// math_macros sourced from "MATH_MACROS"
+    /// extensions sourced from "MARKDOWN_EXTENSIONS", as a comma-separated list (or `none`)
+    /// renderers sourced from "RENDERERS"
+    /// renderer_timeout sourced from "RENDERER_TIMEOUT", in seconds
+    /// cache_size sourced from "CACHE_SIZE", in bytes
+    /// diagnostics sourced from "DIAGNOSTICS" (true or false)
     pub fn source_env(mut self) -> Self {
         if let Some(rootdir) = env::var_os(ROOTDIR_KEY) {
-            eprintln!("rootdir found as {:?}", rootdir);
+            debug!(?rootdir, "rootdir found in environment");
             self.rootdir = PathBuf::from(rootdir);
         }
         if let Some(static_dir) = env::var_os(STATICDIR_KEY) {
-            eprintln!("static dir found as {:?}", static_dir);
+            debug!(?static_dir, "static dir found in environment");
             self.staticdir = PathBuf::from(static_dir);
         }
         if let Some(template_dir) = env::var_os(TEMPLATEDIR_KEY) {
-            eprintln!("template dir found as {:?}", template_dir);
+            debug!(?template_dir, "template dir found in environment");
             self.template_dir = PathBuf::from(template_dir);
         }
+        if let Ok(min_size) = env::var(COMPRESS_MIN_KEY) {
+            match min_size.parse() {
+                Ok(min_size) => self.compress_min_size = min_size,
+                Err(_) => warn!("invalid {COMPRESS_MIN_KEY}: {min_size:?}"),
+            }
+        }
+        if let Ok(skip) = env::var(COMPRESS_SKIP_KEY) {
+            self.compress_skip = split_list(&skip);
+        }
+        if let Ok(symlinks) = env::var(SYMLINKS_KEY) {
+            match symlinks.parse() {
+                Ok(symlinks) => self.symlinks = symlinks,
+                Err(e) => warn!("invalid {SYMLINKS_KEY}: {e}"),
+            }
+        }
+        if let Ok(deny) = env::var(DENY_KEY) {
+            self.deny = split_list(&deny);
+        }
+        if let Ok(allow) = env::var(ALLOW_KEY) {
+            self.allow = split_list(&allow);
+        }
+        if let Ok(format) = env::var(ACCESS_LOG_KEY) {
+            match format.parse() {
+                Ok(format) => self.access_log = Some(format),
+                Err(e) => warn!("invalid {ACCESS_LOG_KEY}: {e}"),
+            }
+        }
+        if let Some(cert) = env::var_os(TLS_CERT_KEY) {
+            self.tls_cert = Some(PathBuf::from(cert));
+        }
+        if let Some(key) = env::var_os(TLS_KEY_KEY) {
+            self.tls_key = Some(PathBuf::from(key));
+        }
+        if let Ok(port) = env::var(REDIRECT_PORT_KEY) {
+            match port.parse() {
+                Ok(port) => self.redirect_port = Some(port),
+                Err(_) => warn!("invalid {REDIRECT_PORT_KEY}: {port:?}"),
+            }
+        }
+        if let Ok(theme) = env::var(HIGHLIGHT_THEME_KEY) {
+            self.highlight_theme = theme;
+        }
+        if let Some(dir) = env::var_os(SYNTAX_DIR_KEY) {
+            self.syntax_dir = Some(PathBuf::from(dir));
+        }
+        if let Ok(delims) = env::var(MATH_INLINE_KEY) {
+            match delims.parse() {
+                Ok(delims) => self.math_inline = delims,
+                Err(e) => warn!("invalid {MATH_INLINE_KEY}: {e}"),
+            }
+        }
+        if let Ok(delims) = env::var(MATH_DISPLAY_KEY) {
+            match delims.parse() {
+                Ok(delims) => self.math_display = delims,
+                Err(e) => warn!("invalid {MATH_DISPLAY_KEY}: {e}"),
+            }
+        }
+        if let Ok(mode) = env::var(MATH_MODE_KEY) {
+            match mode.parse() {
+                Ok(mode) => self.math_mode = mode,
+                Err(e) => warn!("invalid {MATH_MODE_KEY}: {e}"),
+            }
+        }
+        if let Some(macros) = env::var_os(MATH_MACROS_KEY) {
+            self.math_macros = Some(PathBuf::from(macros));
+        }
+        if let Ok(extensions) = env::var(EXTENSIONS_KEY) {
+            match extensions.parse() {
+                Ok(extensions) => self.extensions = extensions,
+                Err(e) => warn!("invalid {EXTENSIONS_KEY}: {e}"),
+            }
+        }
+        if let Some(renderers) = env::var_os(RENDERERS_KEY) {
+            self.renderers = Some(PathBuf::from(renderers));
+        }
+        if let Ok(timeout) = env::var(RENDERER_TIMEOUT_KEY) {
+            match timeout.parse() {
+                Ok(timeout) => self.renderer_timeout = timeout,
+                Err(_) => warn!("invalid {RENDERER_TIMEOUT_KEY}: {timeout:?}"),
+            }
+        }
+        if let Ok(size) = env::var(CACHE_SIZE_KEY) {
+            match size.parse() {
+                Ok(size) => self.cache_size = size,
+                Err(_) => warn!("invalid {CACHE_SIZE_KEY}: {size:?}"),
+            }
+        }
+        if let Ok(diagnostics) = env::var(DIAGNOSTICS_KEY) {
+            match diagnostics.parse() {
+                Ok(diagnostics) => self.diagnostics = diagnostics,
+                Err(_) => warn!("invalid {DIAGNOSTICS_KEY}: {diagnostics:?}"),
+            }
+        }
         self
     }
 
@@ -119,7 +446,7 @@ impl ConfigBuilder {
     }
 
     pub fn set_address(&mut self, addr: &SocketAddr) -> &ConfigBuilder {
-            self.addr = addr.clone();
+            self.addr = *addr;
             self
         }
 
@@ -134,6 +461,130 @@ impl ConfigBuilder {
             self
         }
 
+    /// Set the smallest response size that will be compressed
+    pub fn set_compress_min_size(&mut self, size: u64) -> &ConfigBuilder {
+        self.compress_min_size = size;
+        self
+    }
+
+    /// Set the media types that are never compressed
+    pub fn set_compress_skip(&mut self, skip: &[String]) -> &ConfigBuilder {
+        self.compress_skip = skip.to_vec();
+        self
+    }
+
+    /// Set which symbolic links may be followed
+    pub fn set_symlinks(&mut self, policy: SymlinkPolicy) -> &ConfigBuilder {
+        self.symlinks = policy;
+        self
+    }
+
+    /// Set the patterns of paths that are never served, replacing the defaults
+    pub fn set_deny(&mut self, patterns: &[String]) -> &ConfigBuilder {
+        self.deny = patterns.to_vec();
+        self
+    }
+
+    /// Set the patterns of paths that are served even if they match a denied pattern
+    pub fn set_allow(&mut self, patterns: &[String]) -> &ConfigBuilder {
+        self.allow = patterns.to_vec();
+        self
+    }
+
+    /// Set the format of the access log, or `None` to disable it
+    pub fn set_access_log(&mut self, format: Option<AccessLogFormat>) -> &ConfigBuilder {
+        self.access_log = format;
+        self
+    }
+
+    /// Serve HTTPS with the PEM certificate chain and private key
+    pub fn set_tls(&mut self, cert: &Path, key: &Path) -> &ConfigBuilder {
+        self.tls_cert = Some(PathBuf::from(cert));
+        self.tls_key = Some(PathBuf::from(key));
+        self
+    }
+
+    /// Listen for plain HTTP on `port`, only to redirect to HTTPS
+    pub fn set_redirect_port(&mut self, port: u16) -> &ConfigBuilder {
+        self.redirect_port = Some(port);
+        self
+    }
+
+    /// Set the theme for highlighting code: a built-in theme, a `.tmTheme` file, or `none`
+    pub fn set_highlight_theme(&mut self, theme: &str) -> &ConfigBuilder {
+        self.highlight_theme = theme.to_string();
+        self
+    }
+
+    /// Set a directory of extra `.sublime-syntax` definitions
+    pub fn set_syntax_dir(&mut self, path: &Path) -> &ConfigBuilder {
+        self.syntax_dir = Some(PathBuf::from(path));
+        self
+    }
+
+    /// Set the delimiters of inline math, e.g. `$` or `\( \)`
+    pub fn set_math_inline(&mut self, delims: &Delimiters) -> &ConfigBuilder {
+        self.math_inline = delims.clone();
+        self
+    }
+
+    /// Set the delimiters of display math, e.g. `$$` or `\[ \]`
+    pub fn set_math_display(&mut self, delims: &Delimiters) -> &ConfigBuilder {
+        self.math_display = delims.clone();
+        self
+    }
+
+    /// Set whether math is typeset by the client, or rendered to MathML by the server
+    pub fn set_math_mode(&mut self, mode: MathMode) -> &ConfigBuilder {
+        self.math_mode = mode;
+        self
+    }
+
+    /// Set a TOML file of LaTeX macros, for rendering MathML
+    pub fn set_math_macros(&mut self, path: &Path) -> &ConfigBuilder {
+        self.math_macros = Some(PathBuf::from(path));
+        self
+    }
+
+    /// Set the markdown extensions
+    pub fn set_extensions(&mut self, extensions: Extensions) -> &ConfigBuilder {
+        self.extensions = extensions;
+        self
+    }
+
+    /// Set the file of commands that render code blocks
+    pub fn set_renderers(&mut self, path: &Path) -> &ConfigBuilder {
+        self.renderers = Some(PathBuf::from(path));
+        self
+    }
+
+    /// Set how many seconds the commands that render code blocks may run for
+    pub fn set_renderer_timeout(&mut self, seconds: u64) -> &ConfigBuilder {
+        self.renderer_timeout = seconds;
+        self
+    }
+
+    /// Set how many bytes of rendered notes are kept in memory
+    pub fn set_cache_size(&mut self, size: u64) -> &ConfigBuilder {
+        self.cache_size = size;
+        self
+    }
+
+    /// Set whether the broken-link report and the cache's statistics are served
+    pub fn set_diagnostics(&mut self, diagnostics: bool) -> &ConfigBuilder {
+        self.diagnostics = diagnostics;
+        self
+    }
+
+}
+
+/// Splits a comma-separated list from the environment
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect()
 }
 
 
@@ -193,6 +644,131 @@ mod tests {
         assert_eq!(built.addr.port(), SocketAddr::from(addr_source).port())
     }
 
+    #[test]
+    fn builder_sets_compression() {
+        let mut built = Config::builder();
+        built.set_compress_min_size(10);
+        built.set_compress_skip(&["image/*".to_string()]);
+        let built = built.build();
+        assert_eq!(built.compress_min_size, 10);
+        assert_eq!(built.compress_skip, vec!["image/*".to_string()]);
+    }
+
+    #[test]
+    fn builder_sets_symlinks() {
+        let mut built = Config::builder();
+        built.set_symlinks(SymlinkPolicy::Deny);
+        assert_eq!(built.build().symlinks, SymlinkPolicy::Deny);
+    }
+
+    #[test]
+    fn parses_symlink_policy() {
+        assert_eq!("within-root".parse(), Ok(SymlinkPolicy::WithinRoot));
+        assert_eq!("ALL".parse(), Ok(SymlinkPolicy::All));
+        assert!("sometimes".parse::<SymlinkPolicy>().is_err());
+    }
+
+    #[test]
+    fn builder_denies_dotfiles_by_default() {
+        let built = Config::builder().build();
+        assert!(!built.filter.is_allowed(&PathBuf::from(".git/config")));
+    }
+
+    #[test]
+    fn builder_sets_path_filter() {
+        let mut built = Config::builder();
+        built.set_root(&PathBuf::from("rootdir"));
+        built.set_deny(&["private".to_string()]);
+        built.set_allow(&[".well-known".to_string()]);
+        let built = built.build();
+        assert!(!built.filter.is_allowed(&PathBuf::from("private/diary.md")));
+        assert!(built.filter.is_allowed(&PathBuf::from(".well-known")));
+    }
+
+    #[test]
+    fn builder_sets_access_log() {
+        assert_eq!(Config::builder().build().access_log, None);
+        let mut built = Config::builder();
+        built.set_access_log(Some(AccessLogFormat::Combined));
+        assert_eq!(built.build().access_log, Some(AccessLogFormat::Combined));
+    }
+
+    #[test]
+    fn builder_sets_tls() {
+        assert_eq!(Config::builder().build().tls, None);
+        let mut built = Config::builder();
+        built.set_tls(&PathBuf::from("cert.pem"), &PathBuf::from("key.pem"));
+        built.set_redirect_port(8080);
+        let built = built.build();
+        assert_eq!(built.tls, Some(TlsConfig {
+            cert: PathBuf::from("cert.pem"),
+            key: PathBuf::from("key.pem"),
+        }));
+        assert_eq!(built.redirect_port, Some(8080));
+    }
+
+    #[test]
+    fn builder_sets_highlighting() {
+        let mut built = Config::builder();
+        built.set_highlight_theme("base16-ocean.dark");
+        built.set_syntax_dir(&PathBuf::from("syntaxes"));
+        let built = built.build();
+        assert_eq!(built.highlight_theme, "base16-ocean.dark");
+        assert_eq!(built.syntax_dir, Some(PathBuf::from("syntaxes")));
+    }
+
+    #[test]
+    fn builder_sets_math() {
+        assert_eq!(Config::builder().build().math_inline.to_string(), math::DEFAULT_INLINE);
+        let mut built = Config::builder();
+        built.set_math_inline(&"$".parse().unwrap());
+        built.set_math_display(&"none".parse().unwrap());
+        built.set_math_mode(MathMode::Mathml);
+        built.set_math_macros(&PathBuf::from("macros.toml"));
+        let built = built.build();
+        assert_eq!(built.math_inline.to_string(), "$");
+        assert!(built.math_display.0.is_empty());
+        assert_eq!(built.math_mode, MathMode::Mathml);
+        assert_eq!(built.math_macros, Some(PathBuf::from("macros.toml")));
+    }
+
+    #[test]
+    fn builder_sets_extensions() {
+        let mut built = Config::builder();
+        built.set_extensions("tables, smart-punctuation".parse().unwrap());
+        assert_eq!(built.build().extensions.to_string(), "tables, smart-punctuation");
+    }
+
+    #[test]
+    fn builder_sets_renderers() {
+        let mut built = Config::builder();
+        built.set_renderers(&PathBuf::from("renderers.toml"));
+        built.set_renderer_timeout(3);
+        let built = built.build();
+        assert_eq!(built.renderers, Some(PathBuf::from("renderers.toml")));
+        assert_eq!(built.renderer_timeout, 3);
+    }
+
+    #[test]
+    fn builder_sets_cache_size() {
+        let mut built = Config::builder();
+        built.set_cache_size(0);
+        assert_eq!(built.build().cache_size, 0);
+    }
+
+    #[test]
+    fn builder_sets_diagnostics() {
+        assert!(!Config::builder().build().diagnostics);
+        let mut built = Config::builder();
+        built.set_diagnostics(true);
+        assert!(built.build().diagnostics);
+    }
+
+    #[test]
+    fn splits_lists() {
+        assert_eq!(split_list(" image/png, video/*,,"), vec!["image/png", "video/*"]);
+    }
+
     mod env_tests {
         use super::super::*;
         extern crate scopeguard;
@@ -1,70 +1,235 @@
 //! The context / state for the server
 
-use std::sync::RwLock;
-use crate::config::Config;
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock, atomic::{AtomicU64, Ordering}},
+    time::{Duration, Instant},
+};
+use crate::{
+    cache::{Key, RenderCache, Stamp},
+    config::Config,
+    conditional::fingerprint,
+    filter::PathFilter,
+    index::{Backlink, NoteIndex},
+    linkcheck::{self, Report},
+    markdown::{Note, Renderer},
+    wikilink::WikiLinks,
+};
 use tera::Tera;
+use tracing::{debug, error, warn};
 
 use std::{
     path::{Path, PathBuf, StripPrefixError},
     ffi::{OsStr, OsString},
 };
 
-use walkdir::{WalkDir, DirEntry};
+use walkdir::WalkDir;
 
 use serde::Serialize;
 
+/// How long a walk of the web root is used before a request walks it again
+const TREE_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
 pub struct ServerContext {
     pub config: Config,
     pub tera: RwLock<Tera>,
     pub roottree: RwLock<Directory>,
+    /// Metadata of every note, refreshed along with `roottree`
+    pub index: RwLock<NoteIndex>,
+    /// Wiki links to every file in `roottree`, refreshed along with it
+    wiki: RwLock<WikiLinks>,
+    /// When `roottree` was last walked, locked for as long as a walk takes
+    last_refresh: tokio::sync::Mutex<Instant>,
+    /// Shared with the blocking threads that notes are rendered on
+    pub renderer: Arc<Renderer>,
+    /// Notes rendered by [ServerContext::render_note]
+    pub cache: RenderCache,
+    /// The last link report, with the state of the tree and index it was made from
+    link_report: tokio::sync::Mutex<Option<(u64, Arc<Report>)>>,
+    /// Fingerprints of the state that goes into rendered pages, for entity tags
+    template_state: AtomicU64,
+    tree_state: AtomicU64,
 }
 
 impl ServerContext {
     pub fn new(config: Config) -> Self {
         // Set up templates
         let template_glob = config.template_dir.join("**/*.html");
-        let tera = match Tera::new(&template_glob.to_str().expect("Templates could not be parsed")) {
+        let tera = match Tera::new(template_glob.to_str().expect("Templates could not be parsed")) {
             Ok(t) => RwLock::new(t),
-            Err(e) => {eprintln!("{e}"); panic!()},
+            Err(e) => {error!("{e}"); panic!()},
         };
         // Get web root contents
-        let rt = walk_dir(&config.rootdir, true);
-        let roottree = RwLock::new(rt.expect("Could not walk the web root"));
-        return Self { config, tera, roottree };
+        let rt = walk_dir(&config.rootdir, true, &config.filter, &config.rootdir);
+        let rt = rt.expect("Could not walk the web root");
+        let tree_state = AtomicU64::new(fingerprint(&rt));
+        let index = RwLock::new(NoteIndex::new(&rt, &config));
+        let wiki = RwLock::new(WikiLinks::new(&rt, ""));
+        let last_refresh = tokio::sync::Mutex::new(Instant::now());
+        let roottree = RwLock::new(rt);
+        let template_state = AtomicU64::new(template_fingerprint(&config.template_dir));
+        let renderer = Arc::new(Renderer::new(&config));
+        let cache = RenderCache::new(config.cache_size);
+        let link_report = tokio::sync::Mutex::new(None);
+        Self {
+            config, tera, roottree, index, wiki, last_refresh, renderer, cache, link_report,
+            template_state, tree_state,
+        }
     }
 
-    pub fn reload_templates(&self) {
+    pub fn reload_templates(&self) -> Result<(), tera::Error> {
         let mut lock = self.tera.write().expect("Could not open tera for reloading");
-        match lock.full_reload() {
-            Ok(_) => eprintln!("Templates reloaded"),
-            Err(e) => {eprintln!("{e}"); drop(lock); panic!()},
-        }
+        lock.full_reload()?;
+        debug!("templates reloaded");
+        let state = template_fingerprint(&self.config.template_dir);
+        self.template_state.store(state, Ordering::Relaxed);
+        Ok(())
     }
 
-    pub fn refresh_roottree(&self) {
-        let rt = walk_dir(&self.config.rootdir, true);
-        match rt {
-            Ok(rt) => {
-                let mut lock = self.roottree.write().expect("Could not access roottree for refresh");
-                *lock = rt;
+    /// Walks the web root again, and brings the index and wiki links up to date with it
+    ///
+    /// The walk is done on a blocking thread, with copies that are swapped in once it's done.
+    /// Requests that come in during a walk wait for it instead of starting their own, and a walk
+    /// is used for [TREE_REFRESH_INTERVAL] before another is made.
+    pub async fn refresh_roottree(&self) {
+        let mut last_refresh = self.last_refresh.lock().await;
+        if last_refresh.elapsed() < TREE_REFRESH_INTERVAL {
+            return;
+        }
+        let config = self.config.clone();
+        let mut index = self.index.read().expect("Could not access index for refresh").clone();
+        let walked = tokio::task::spawn_blocking(move || {
+            let rt = walk_dir(&config.rootdir, true, &config.filter, &config.rootdir)?;
+            index.refresh(&rt, &config);
+            let wiki = WikiLinks::new(&rt, "");
+            Ok::<_, StripPrefixError>((rt, index, wiki))
+        }).await;
+        match walked {
+            Ok(Ok((rt, index, wiki))) => {
+                let state = fingerprint(&rt);
+                *self.index.write().expect("Could not access index for refresh") = index;
+                *self.wiki.write().expect("Could not access wiki links for refresh") = wiki;
+                *self.roottree.write().expect("Could not access roottree for refresh") = rt;
+                // NOTE: the state goes last, so nothing is cached under it with the old tree
+                self.tree_state.store(state, Ordering::Relaxed);
             },
-            Err(e) => eprintln!("Error in tracing web root: {e}"),
+            Ok(Err(e)) => error!("error in walking the web root: {e}"),
+            Err(e) => error!("walking the web root failed: {e}"),
+        }
+        *last_refresh = Instant::now();
+    }
+
+    /// Fingerprint of the loaded templates
+    pub fn template_state(&self) -> u64 {
+        self.template_state.load(Ordering::Relaxed)
+    }
+
+    /// Fingerprint of the web-root tree, as of the last refresh
+    pub fn tree_state(&self) -> u64 {
+        self.tree_state.load(Ordering::Relaxed)
+    }
+
+    /// Renders the note at `path`, with its wiki links resolved against the web root
+    ///
+    /// The note is only rendered again if the file or the tree has changed since it was cached.
+    /// [Note::incomplete] notes aren't cached. Rendering is done on a blocking thread, since it can
+    /// run external commands.
+    pub async fn render_note(&self, path: &Path) -> std::io::Result<Note> {
+        let canonical = tokio::fs::canonicalize(path).await?;
+        let stamp = Stamp::new(&tokio::fs::metadata(&canonical).await?, self.tree_state());
+        let from = self.strip_path(path)
+            .map(|p| p.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let key = Key::new(canonical, &from);
+        if let Some(note) = stamp.as_ref().and_then(|stamp| self.cache.get(&key, stamp)) {
+            return Ok(note);
+        }
+        let mut wiki = self.wiki.read().expect("could not read wiki links").clone();
+        wiki.set_from(&from);
+        let source = tokio::fs::read_to_string(path).await?;
+        let renderer = self.renderer.clone();
+        let note = tokio::task::spawn_blocking(move || renderer.render(source, &from, &wiki)).await
+            .map_err(std::io::Error::other)?;
+        if let Some(stamp) = stamp.filter(|_| !note.incomplete) {
+            self.cache.insert(key, stamp, &note);
+        }
+        Ok(note)
+    }
+
+    /// The broken links of every note, as of the last refresh
+    ///
+    /// The notes come from [ServerContext::render_note], so only changed ones are rendered again,
+    /// and the report is kept until the tree or a note changes. Requests that come in while a
+    /// report is made wait for it.
+    pub async fn link_report(&self) -> Arc<Report> {
+        let index_state = self.index.read().expect("could not read note index").state();
+        let state = fingerprint(&[self.tree_state(), index_state]);
+        let mut last = self.link_report.lock().await;
+        if let Some((_, report)) = last.as_ref().filter(|(s, _)| *s == state) {
+            return report.clone();
         }
+        let files: Vec<String> = self.roottree.read().expect("could not read web-root tree")
+            .all_files().into_iter()
+            .filter(|f| f.path().ends_with(".md"))
+            .map(|f| f.path().to_string())
+            .collect();
+        let mut notes = HashMap::new();
+        let mut order = Vec::new();
+        for url_path in files {
+            let path = self.config.rootdir.join(url_path.trim_start_matches('/'));
+            match self.render_note(&path).await {
+                Ok(note) => { notes.insert(path.clone(), note); },
+                Err(e) => {
+                    warn!("could not read {url_path}: {e}");
+                    continue;
+                },
+            }
+            order.push((url_path, path));
+        }
+        let config = self.config.clone();
+        let check = move || linkcheck::check_notes(&config, &order, &notes);
+        let checked = tokio::task::spawn_blocking(check).await;
+        let report = Arc::new(checked.unwrap_or_else(|e| {
+            error!("checking links failed: {e}");
+            Report::default()
+        }));
+        *last = Some((state, report.clone()));
+        report
+    }
+
+    /// The notes linking to the note at `path`, as of the last refresh
+    pub fn backlinks(&self, path: &Path) -> Vec<Backlink> {
+        let Some(url) = self.strip_path(path) else { return Vec::new() };
+        self.index.read().expect("could not read note index").backlinks(&url.to_string_lossy())
     }
 
     pub fn strip_path(&self, path: &Path) -> Option<OsString> {
         let stripped = path.strip_prefix(&self.config.rootdir);
-        return stripped.ok().map(make_abs);
+        stripped.ok().map(make_abs)
     }
 
 }
 
+/// Fingerprint of the name, size and modification time of every template file
+fn template_fingerprint(template_dir: &Path) -> u64 {
+    let state: Vec<_> = WalkDir::new(template_dir)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let metadata = e.metadata().ok()?;
+            Some((e.into_path(), metadata.len(), metadata.modified().ok()))
+        })
+        .collect();
+    fingerprint(&state)
+}
+
 // Filesystem tree context {{{ 
 /* Using Separate structs for files and directories makes it much easier to build from WalkDir
  * In addition, it forces my to store separate lists of subdirectories and files, which means the
  * values are already sorted by type.
  */
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Hash, Serialize)]
 pub struct Directory { 
     name: String, 
     // NOTE(jladan): A path is best for creating the tree, but if it is used for links, this will
@@ -74,7 +239,7 @@ pub struct Directory {
     files: Vec<File>,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Hash, Serialize)]
 pub struct File { 
     name: String, 
     path: String,
@@ -92,6 +257,25 @@ impl Directory {
     }
 }
 
+impl Directory {
+    /// Names and paths of the immediate subdirectories and files
+    pub fn entries(&self) -> impl Iterator<Item = (&str, String)> {
+        let dirs = self.dirs.iter()
+            .map(|d| (d.name.as_str(), d.path.to_string_lossy().to_string()));
+        let files = self.files.iter().map(|f| (f.name.as_str(), f.path.clone()));
+        dirs.chain(files)
+    }
+
+    /// Every file in the tree, depth first
+    pub fn all_files(&self) -> Vec<&File> {
+        let mut files: Vec<&File> = self.files.iter().collect();
+        for dir in &self.dirs {
+            files.extend(dir.all_files());
+        }
+        files
+    }
+}
+
 impl File {
     fn new(name: &OsStr, path: OsString) -> Self {
         Self { 
@@ -100,6 +284,14 @@ impl File {
             media_type: None
         }
     }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
 }
 
 
@@ -118,17 +310,27 @@ walk, so tree-structure can be determined through the path prefix.
 /// ## Arguments:
 /// - `path: &Path`: the path to the directory
 /// - `absolute: bool` -- whether the returned paths should start with "/"
+/// - `filter: &PathFilter` -- paths to leave out, as in `Config::filter`
+/// - `root: &Path` -- the web root, which paths are made relative to before `filter` checks them
 ///
-pub fn walk_dir(path: &Path, absolute: bool) -> Result<Directory, StripPrefixError> {
+pub fn walk_dir(
+    path: &Path,
+    absolute: bool,
+    filter: &PathFilter,
+    root: &Path,
+) -> Result<Directory, StripPrefixError> {
     // Prefix to strip from all paths
     let prefix = path;
     // Stack for depth-first search
     let mut dirstack: Vec<Directory> = Vec::new();
-    // Set up walkdir iterator, sorted by filename with no hidden files
+    // Set up walkdir iterator, sorted by filename with no denied files
+    // NOTE: the walk's starting point is always listed, even if it is filtered
     let mut walker = WalkDir::new(prefix)
         .sort_by(|a,b| a.file_name().to_ascii_lowercase().cmp(&b.file_name().to_ascii_lowercase()))
         .into_iter()
-        .filter_entry(|e| !is_hidden(e))
+        .filter_entry(|e| {
+            e.depth() == 0 || e.path().strip_prefix(root).is_ok_and(|p| filter.is_allowed(p))
+        })
         .filter_map(|e| e.ok());
     let mut curdir: Directory;
     if let Some(entry) = walker.next() {
@@ -184,7 +386,7 @@ pub fn walk_dir(path: &Path, absolute: bool) -> Result<Directory, StripPrefixErr
         curdir = prevdir;
     }
     if absolute { *curdir.path.as_mut_os_string() = make_abs(&curdir.path) }
-    return Ok(curdir)
+    Ok(curdir)
 }
 
 /// Adds a trailing slash to all directories
@@ -200,11 +402,4 @@ fn make_abs(a: &Path) -> OsString {
     built
 }
 
-/// Checks if a file starts with "."
-fn is_hidden(entry: &DirEntry) -> bool {
-    entry.file_name()
-        .to_str()
-        .map(|s| s.starts_with("."))
-        .unwrap_or(false)
-}
 // }}}
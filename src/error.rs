@@ -0,0 +1,118 @@
+//! Errors in handling a request
+//!
+//! Handlers return [Error] instead of building error responses themselves, so that every failure
+//! gets the right status code and goes through the same (templated) error page.
+
+use std::{fmt, io, path::StripPrefixError};
+
+use hyper::StatusCode;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Nothing exists at the requested path
+    NotFound,
+    /// The path exists, but may not be served
+    Forbidden,
+    /// The file can't be read as the type it claims to be, e.g. a markdown file which isn't UTF-8
+    UnsupportedMediaType,
+    /// Any other filesystem error
+    Io(io::Error),
+    /// Failure in loading or rendering a template
+    Template(tera::Error),
+}
+
+impl Error {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::NotFound => StatusCode::NOT_FOUND,
+            Error::Forbidden => StatusCode::FORBIDDEN,
+            Error::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Error::Io(_) | Error::Template(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// A message that is safe to show to the client
+    ///
+    /// Internal errors only give their status, since their details may include local paths.
+    pub fn public_message(&self) -> String {
+        match self {
+            Error::NotFound => "The requested page could not be found".to_string(),
+            Error::Forbidden => "You do not have permission to view this page".to_string(),
+            Error::UnsupportedMediaType => "The file could not be read as text".to_string(),
+            Error::Io(_) => "The file could not be read".to_string(),
+            Error::Template(_) => "Error in applying template".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotFound => write!(f, "not found"),
+            Error::Forbidden => write!(f, "forbidden"),
+            Error::UnsupportedMediaType => write!(f, "unsupported media type"),
+            Error::Io(e) => write!(f, "io error: {e}"),
+            Error::Template(e) => {
+                // NOTE: tera hides the useful part of the message in the source
+                write!(f, "template error: {e}")?;
+                let mut source = std::error::Error::source(e);
+                while let Some(e) = source {
+                    write!(f, ": {e}")?;
+                    source = e.source();
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Template(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NotFound => Error::NotFound,
+            io::ErrorKind::PermissionDenied => Error::Forbidden,
+            // `read_to_string` on a file that isn't UTF-8
+            io::ErrorKind::InvalidData => Error::UnsupportedMediaType,
+            _ => Error::Io(e),
+        }
+    }
+}
+
+impl From<tera::Error> for Error {
+    fn from(e: tera::Error) -> Self {
+        Error::Template(e)
+    }
+}
+
+impl From<StripPrefixError> for Error {
+    fn from(_: StripPrefixError) -> Self {
+        // NOTE: only happens for paths outside the web root
+        Error::NotFound
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn io_errors_map_to_status() {
+        let status = |kind| Error::from(io::Error::from(kind)).status();
+        assert_eq!(status(io::ErrorKind::NotFound), StatusCode::NOT_FOUND);
+        assert_eq!(status(io::ErrorKind::PermissionDenied), StatusCode::FORBIDDEN);
+        assert_eq!(status(io::ErrorKind::InvalidData), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(status(io::ErrorKind::Interrupted), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
@@ -0,0 +1,267 @@
+//! Rendering fenced code blocks with external commands
+//!
+//! Diagrams are often written as code, like ```` ```dot ```` or ```` ```mermaid ```` blocks. A TOML
+//! file given as `Config::renderers` maps a block's language to a command, which is given the code
+//! on stdin and writes SVG or HTML on stdout:
+//!
+//! ```toml
+//! dot = "dot -Tsvg"
+//! plantuml = ["plantuml", "-tsvg", "-pipe"]
+//! ```
+//!
+//! Commands are split on whitespace (or given as a list) and run directly, not through a shell.
+//! Their output is inlined in the note, and kept by a hash of the command and code, so a diagram is
+//! only drawn again when it changes. A command that fails, or runs for longer than
+//! `Config::renderer_timeout`, shows its error in place of the block, and the note is marked as
+//! incomplete so that it isn't kept with the error.
+
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{Read, Write},
+    path::Path,
+    process::{Command, Stdio},
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use pulldown_cmark::{Event, Tag, CodeBlockKind, CowStr, escape::escape_html};
+use serde::Deserialize;
+use tracing::{debug, warn};
+
+use crate::{conditional::fingerprint, highlight::class_name};
+
+/// Outputs kept at once; the cache is emptied when it's full
+const CACHE_ENTRIES: usize = 256;
+
+/// How often a running command is checked on
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+pub struct ExternalRenderers {
+    /// Command and arguments by language
+    commands: HashMap<String, Vec<String>>,
+    timeout: Duration,
+    /// Outputs by the fingerprint of the command and code
+    cache: Mutex<HashMap<u64, String>>,
+}
+
+/// A command as written in the TOML file
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CommandLine {
+    Line(String),
+    Args(Vec<String>),
+}
+
+impl ExternalRenderers {
+    /// Loads the commands from a TOML file
+    ///
+    /// Problems with the file are logged, and code blocks are left alone.
+    pub fn new(path: &Path, timeout: Duration) -> Self {
+        let commands = load_commands(path).unwrap_or_else(|e| {
+            warn!("{e}; code blocks won't be rendered by commands");
+            HashMap::new()
+        });
+        debug!(languages = ?commands.keys().collect::<Vec<_>>(), "loaded external renderers");
+        Self::from_commands(commands, timeout)
+    }
+
+    fn from_commands(commands: HashMap<String, Vec<String>>, timeout: Duration) -> Self {
+        Self { commands, timeout, cache: Mutex::new(HashMap::new()) }
+    }
+
+    /// Replaces fenced code blocks of the configured languages with their commands' output
+    ///
+    /// Also gives whether any of the commands failed.
+    pub fn render_events<'a>(&self, events: impl Iterator<Item = Event<'a>>) -> (Vec<Event<'a>>, bool) {
+        let mut out = Vec::new();
+        let mut failed = false;
+        // The language and code of the block being collected
+        let mut block: Option<(String, String)> = None;
+        for event in events {
+            match (event, block.as_mut()) {
+                (Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))), None)
+                    if info.split_whitespace().next().is_some_and(|lang| self.commands.contains_key(lang)) =>
+                {
+                    let lang = info.split_whitespace().next().unwrap_or_default().to_string();
+                    block = Some((lang, String::new()));
+                },
+                (Event::Text(text), Some((_, code))) => code.push_str(&text),
+                (Event::End(Tag::CodeBlock(_)), Some(_)) => {
+                    let (lang, code) = block.take().expect("checked by the match");
+                    let html = self.render(&lang, &code).unwrap_or_else(|error| {
+                        failed = true;
+                        error
+                    });
+                    out.push(Event::Html(CowStr::from(html)));
+                },
+                (event, _) => out.push(event),
+            }
+        }
+        (out, failed)
+    }
+
+    /// The html for a block, from the cache or by running its command
+    ///
+    /// When the command fails, the error is html showing the failure.
+    fn render(&self, lang: &str, code: &str) -> Result<String, String> {
+        let command = &self.commands[lang];
+        let key = fingerprint(&(command, code));
+        if let Some(html) = self.cache.lock().expect("could not lock the renderer cache").get(&key) {
+            return Ok(html.clone());
+        }
+        match run(command, code, self.timeout) {
+            Ok(output) => {
+                let html = format!("<div class=\"rendered rendered-{}\">{}</div>\n", class_name(lang), strip_prolog(&output));
+                let mut cache = self.cache.lock().expect("could not lock the renderer cache");
+                if cache.len() >= CACHE_ENTRIES {
+                    cache.clear();
+                }
+                cache.insert(key, html.clone());
+                Ok(html)
+            },
+            // NOTE: failures aren't cached, since they can be fixed without changing the note
+            Err(e) => {
+                warn!("rendering a {lang} block failed: {e}");
+                Err(error_html(lang, code, &e))
+            },
+        }
+    }
+}
+
+/// Reads the TOML table of commands
+fn load_commands(path: &Path) -> Result<HashMap<String, Vec<String>>, String> {
+    let error = |e: &dyn fmt::Display| format!("could not load renderers from {}: {e}", path.display());
+    let contents = fs::read_to_string(path).map_err(|e| error(&e))?;
+    let table: HashMap<String, CommandLine> = toml::from_str(&contents).map_err(|e| error(&e))?;
+    let mut commands = HashMap::new();
+    for (lang, command) in table {
+        let args = match command {
+            CommandLine::Line(line) => line.split_whitespace().map(String::from).collect(),
+            CommandLine::Args(args) => args,
+        };
+        if args.is_empty() {
+            return Err(error(&format!("the command for {lang} is empty")));
+        }
+        commands.insert(lang, args);
+    }
+    Ok(commands)
+}
+
+/// Runs a command with `input` on stdin, giving its stdout
+///
+/// The command is killed if it runs for longer than `timeout`. An exit with an error gives its
+/// stderr.
+fn run(command: &[String], input: &str, timeout: Duration) -> Result<String, String> {
+    let (program, args) = command.split_first().ok_or("no command")?;
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("could not run {program}: {e}"))?;
+    // NOTE: the pipes are written and read on their own threads, so that a command
+    // filling one pipe while we wait on another can't deadlock
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let input = input.to_string();
+    // A command that doesn't read its input closes the pipe, which isn't an error here
+    thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let stdout = read_all(child.stdout.take().expect("stdout is piped"));
+    let stderr = read_all(child.stderr.take().expect("stderr is piped"));
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if Instant::now() >= deadline => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!("{program} timed out after {}s", timeout.as_secs_f64()));
+            },
+            Ok(None) => thread::sleep(POLL_INTERVAL),
+            Err(e) => return Err(format!("could not wait for {program}: {e}")),
+        }
+    };
+    let stdout = stdout.join().expect("the reading thread doesn't panic");
+    let stderr = stderr.join().expect("the reading thread doesn't panic");
+    if !status.success() {
+        let stderr = String::from_utf8_lossy(&stderr);
+        let message = stderr.trim();
+        return Err(if message.is_empty() { format!("{program} failed ({status})") } else { message.to_string() });
+    }
+    String::from_utf8(stdout).map_err(|_| format!("the output of {program} is not UTF-8"))
+}
+
+fn read_all(mut pipe: impl Read + Send + 'static) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = pipe.read_to_end(&mut buf);
+        buf
+    })
+}
+
+/// Drops the XML declaration and doctype in front of an SVG, which don't belong inside html
+fn strip_prolog(output: &str) -> &str {
+    let trimmed = output.trim_start();
+    if !(trimmed.starts_with("<?xml") || trimmed.starts_with("<!DOCTYPE")) {
+        return output;
+    }
+    trimmed.find("<svg").map(|i| &trimmed[i..]).unwrap_or(output)
+}
+
+/// The error of a failed command, followed by the block's code
+fn error_html(lang: &str, code: &str, error: &str) -> String {
+    let mut html = format!("<div class=\"rendered-error rendered-{}\"><p>", class_name(lang));
+    escape_html(&mut html, error).expect("writing to a string can't fail");
+    html.push_str("</p><pre><code>");
+    escape_html(&mut html, code).expect("writing to a string can't fail");
+    html.push_str("</code></pre></div>\n");
+    html
+}
+
+// NOTE: the tests run unix tools as their commands
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use pulldown_cmark::{Parser, html};
+
+    fn renderers(commands: &[(&str, &[&str])], timeout: Duration) -> ExternalRenderers {
+        let commands = commands.iter()
+            .map(|(lang, args)| (lang.to_string(), args.iter().map(|a| a.to_string()).collect()))
+            .collect();
+        ExternalRenderers::from_commands(commands, timeout)
+    }
+
+    fn render(source: &str, renderers: &ExternalRenderers) -> String {
+        let mut out = String::new();
+        html::push_html(&mut out, renderers.render_events(Parser::new(source)).0.into_iter());
+        out
+    }
+
+    #[test]
+    fn renders_configured_blocks() {
+        let renderers = renderers(&[("shout", &["tr", "a-z", "A-Z"])], Duration::from_secs(5));
+        assert_eq!(render("```shout\n<b>hi</b>\n```\n", &renderers), "<div class=\"rendered rendered-shout\"><B>HI</B>\n</div>\n");
+        assert_eq!(render("```rust\nfn\n```\n", &renderers), "<pre><code class=\"language-rust\">fn\n</code></pre>\n");
+        assert_eq!(renderers.cache.lock().unwrap().len(), 1);
+        assert_eq!(strip_prolog("<?xml version=\"1.0\"?>\n<!DOCTYPE svg>\n<svg></svg>"), "<svg></svg>");
+    }
+
+    #[test]
+    fn shows_failures() {
+        let renderers = renderers(&[
+            ("slow", &["sleep", "5"]),
+            ("missing", &["no-such-command-here"]),
+            ("fails", &["ls", "/no/such/dir"]),
+        ], Duration::from_millis(100));
+        let html = render("```slow\na < b\n```\n", &renderers);
+        assert_eq!(html, "<div class=\"rendered-error rendered-slow\"><p>sleep timed out after 0.1s</p><pre><code>a &lt; b\n</code></pre></div>\n");
+        assert!(render("```missing\n```\n", &renderers).contains("could not run no-such-command-here"));
+        assert!(render("```fails\n```\n", &renderers).contains("/no/such/dir"));
+        assert!(renderers.cache.lock().unwrap().is_empty());
+        assert!(renderers.render_events(Parser::new("```fails\n```\n")).1);
+        assert!(!renderers.render_events(Parser::new("```text\n```\n")).1);
+    }
+}
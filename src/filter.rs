@@ -0,0 +1,140 @@
+//! Filtering of paths that must never be served
+//!
+//! A [PathFilter] holds glob patterns of denied and allowed paths. It is checked by
+//! `uri::resolve` before any handler runs, and by `context::walk_dir` so that denied files don't
+//! show up in listings either. Both make the path relative to the web root first.
+//!
+//! Patterns without a `/` are matched against every component of the path, so `.*` hides any
+//! dotfile or dot-directory at any depth. Patterns with a `/` are matched against the whole path,
+//! relative to the web root (or static directory). An allow pattern overrides the deny patterns,
+//! e.g. deny `.*` and allow `.well-known`.
+
+use std::path::{Path, PathBuf};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use tracing::warn;
+
+#[derive(Debug, Clone)]
+pub struct PathFilter {
+    deny_patterns: Vec<String>,
+    allow_patterns: Vec<String>,
+    deny: Patterns,
+    allow: Patterns,
+}
+
+/// Compiled patterns, split by what they are matched against
+#[derive(Debug, Clone)]
+struct Patterns {
+    names: GlobSet,
+    paths: GlobSet,
+}
+
+impl Patterns {
+    fn new(patterns: &[String]) -> Self {
+        let mut names = GlobSetBuilder::new();
+        let mut paths = GlobSetBuilder::new();
+        for pattern in patterns {
+            let trimmed = pattern.trim_matches('/');
+            match Glob::new(trimmed) {
+                Ok(glob) if trimmed.contains('/') => { paths.add(glob); },
+                Ok(glob) => { names.add(glob); },
+                Err(e) => warn!("ignoring invalid path pattern {pattern:?}: {e}"),
+            }
+        }
+        Self {
+            names: names.build().unwrap_or_else(|_| GlobSet::empty()),
+            paths: paths.build().unwrap_or_else(|_| GlobSet::empty()),
+        }
+    }
+
+    /// Whether the last component of `path`, or the whole of it, matches
+    fn matches(&self, path: &Path) -> bool {
+        path.file_name().map(|name| self.names.is_match(name)).unwrap_or(false)
+            || self.paths.is_match(path)
+    }
+}
+
+impl PathFilter {
+    pub fn new(deny: &[String], allow: &[String]) -> Self {
+        Self {
+            deny_patterns: deny.to_vec(),
+            allow_patterns: allow.to_vec(),
+            deny: Patterns::new(deny),
+            allow: Patterns::new(allow),
+        }
+    }
+
+    /// Checks a path relative to the web root (or static directory)
+    ///
+    /// Every ancestor is checked as well, so nothing inside a denied directory is allowed.
+    pub fn is_allowed(&self, relpath: &Path) -> bool {
+        let mut prefix = PathBuf::new();
+        for component in relpath.components() {
+            prefix.push(component);
+            if self.deny.matches(&prefix) && !self.allow.matches(&prefix) {
+                return false;
+            }
+        }
+        true
+    }
+
+    pub fn deny_patterns(&self) -> &[String] {
+        &self.deny_patterns
+    }
+
+    pub fn allow_patterns(&self) -> &[String] {
+        &self.allow_patterns
+    }
+}
+
+impl PartialEq for PathFilter {
+    fn eq(&self, other: &Self) -> bool {
+        self.deny_patterns == other.deny_patterns
+            && self.allow_patterns == other.allow_patterns
+    }
+}
+
+impl Eq for PathFilter {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(deny: &[&str], allow: &[&str]) -> PathFilter {
+        let strings = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        PathFilter::new(&strings(deny), &strings(allow))
+    }
+
+    #[test]
+    fn denies_dotfiles_at_any_depth() {
+        let f = filter(&[".*"], &[]);
+        assert!(!f.is_allowed(Path::new(".git/config")));
+        assert!(!f.is_allowed(Path::new("notes/.env")));
+        assert!(!f.is_allowed(Path::new("notes/.obsidian/workspace.json")));
+        assert!(f.is_allowed(Path::new("notes/usage.md")));
+    }
+
+    #[test]
+    fn matches_path_globs_from_the_root() {
+        let f = filter(&["private/*"], &[]);
+        assert!(!f.is_allowed(Path::new("private/diary.md")));
+        assert!(f.is_allowed(Path::new("public/private.md")));
+        assert!(f.is_allowed(Path::new("public/private/diary.md")));
+    }
+
+    #[test]
+    fn allow_overrides_deny() {
+        let f = filter(&[".*"], &[".well-known"]);
+        assert!(f.is_allowed(Path::new(".well-known/security.txt")));
+        assert!(!f.is_allowed(Path::new(".well-known/.secret")));
+        assert!(!f.is_allowed(Path::new(".git/HEAD")));
+    }
+
+    #[test]
+    fn matches_name_globs() {
+        let f = filter(&["*.key", "*~"], &[]);
+        assert!(!f.is_allowed(Path::new("certs/server.key")));
+        assert!(!f.is_allowed(Path::new("notes.md~")));
+        assert!(f.is_allowed(Path::new("notes.md")));
+    }
+}
@@ -3,78 +3,171 @@
 //! For example, if GET Markdown is requested, then the headers are needed to determine the type of
 //! response
 
-use std::{path::Path, ops::Deref, str::FromStr};
+use std::{path::Path, ops::Deref, time::UNIX_EPOCH};
 
 use tokio::fs;
-use hyper::{Body, Response, HeaderMap, http::HeaderValue};
+use hyper::{Body, Response, HeaderMap, header::HeaderValue};
 
-use pulldown_cmark::{Parser, Options, html};
+use serde::Serialize;
+use tracing::{debug, error, trace};
 
 // use tera::Tera;
 
 use crate::{
-    context::ServerContext,
+    context::{ServerContext, Directory},
+    error::{Error, Result},
+    conditional::{self, Validators, fingerprint},
+    markdown::Note,
+    index::{self, Backlink},
     response,
+    accept,
 };
 
 const MARKDOWN_TEMPLATE: &str = "markdown.html";
+const ERROR_TEMPLATE: &str = "error.html";
+const ERROR_CHUNK_TEMPLATE: &str = "error-chunk.html";
+const TAGS_TEMPLATE: &str = "tags.html";
+const TAGS_CHUNK_TEMPLATE: &str = "tags-chunk.html";
+const TAG_TEMPLATE: &str = "tag.html";
+const TAG_CHUNK_TEMPLATE: &str = "tag-chunk.html";
 
-pub fn directory(path: &Path, headers: &HeaderMap, _context: &ServerContext) -> Response<Body> {
-    let accepts = preferred_format(headers);
+/// Template variables shared by every page
+fn page_context(context: &ServerContext) -> tera::Context {
+    let mut tera_context = tera::Context::new();
+    tera_context.insert("highlight_css", &context.renderer.highlighter().is_some());
+    tera_context
+}
+
+pub async fn directory(path: &Path, headers: &HeaderMap, _context: &ServerContext) -> Result<Response<Body>> {
     use  AcceptFormat::*;
-    for af in accepts {
-        match af {
-            PartialHtml => return dir_html(path, _context, true),
-            Html | Any  => return dir_html(path, _context, false),
-            _ => continue,
-        }
+    match preferred_format(headers, &PAGE_FORMATS) {
+        Some(PartialHtml) => dir_html(path, headers, _context, true).await,
+        Some(Html) => dir_html(path, headers, _context, false).await,
+        Some(Json) => dir_json(path, headers, _context),
+        Some(format @ (Markdown | Plain)) => dir_text(path, headers, _context, format),
+        None => Ok(response::not_acceptable(&media_types(&PAGE_FORMATS))),
     }
-    response::not_acceptable()
 }
 
-fn dir_html(path: &Path, context: &ServerContext, partial: bool) -> Response<Body> {
-    let tera = context.tera.read().expect("could not read template engine");
+fn dir_json(path: &Path, headers: &HeaderMap, context: &ServerContext) -> Result<Response<Body>> {
+    let dirtree: Directory = crate::context::walk_dir(path, false, &context.config.filter, &context.config.rootdir)?;
+    let validators = Validators::from_fingerprint("json-dir", &[fingerprint(&dirtree)]);
+    if validators.not_modified(headers) {
+        return Ok(conditional::not_modified(&validators));
+    }
+    let mut resp = response::send_json(&dirtree);
+    validators.apply(resp.headers_mut());
+    Ok(resp)
+}
+
+/// A directory listing as markdown links, or as plain file names
+fn dir_text(path: &Path, headers: &HeaderMap, context: &ServerContext, format: AcceptFormat) -> Result<Response<Body>> {
+    let dirtree: Directory = crate::context::walk_dir(path, false, &context.config.filter, &context.config.rootdir)?;
+    let validators = Validators::from_fingerprint(format.media_type(), &[fingerprint(&dirtree)]);
+    if validators.not_modified(headers) {
+        return Ok(conditional::not_modified(&validators));
+    }
+    let listing: String = dirtree.entries()
+        .map(|(name, path)| match format {
+            AcceptFormat::Markdown => format!("- [{name}]({})\n", url_escape::encode_path(&path)),
+            _ => format!("{name}\n"),
+        })
+        .collect();
+    let mut resp = response::send_text(listing, format.media_type());
+    validators.apply(resp.headers_mut());
+    Ok(resp)
+}
+
+async fn dir_html(path: &Path, headers: &HeaderMap, context: &ServerContext, partial: bool) -> Result<Response<Body>> {
     if !partial {
-        context.refresh_roottree();
+        context.refresh_roottree().await;
     }
+    let dirtree = crate::context::walk_dir(path, false, &context.config.filter, &context.config.rootdir)?;
+    // NOTE: the listing has no single file behind it, so the tag comes from its contents
+    let validators = if partial {
+        Validators::from_fingerprint("partial-dir", &[fingerprint(&dirtree), context.template_state()])
+    } else {
+        Validators::from_fingerprint("dir", &[fingerprint(&dirtree), context.template_state(), context.tree_state()])
+    };
+    if validators.not_modified(headers) {
+        return Ok(conditional::not_modified(&validators));
+    }
+    let tera = context.tera.read().expect("could not read template engine");
     let root_tree = context.roottree.read().expect("could not read web-root tree");
-    let dirtree = crate::context::walk_dir(path, false).expect("failure to trace directory");
-    let mut context = tera::Context::new();
+    let mut context = page_context(context);
     context.insert("dirtree", &root_tree.deref());
     context.insert("dir_contents", &dirtree);
     let rendered = if partial {
-        tera.render("directory-chunk.html", &context)
+        tera.render("directory-chunk.html", &context)?
     } else {
-        tera.render("directory.html", &context)
+        tera.render("directory.html", &context)?
     };
-    match rendered {
-        Ok(contents) => response::send_html(contents),
-        Err(e) => {eprintln!("{e}"); response::server_error("")},
-    }
+    let mut resp = response::send_html(rendered);
+    validators.apply(resp.headers_mut());
+    Ok(resp)
 }
 
 // General files {{{
 
-pub async fn file(path: &Path, headers: &HeaderMap, context: &ServerContext) -> Response<Body> {
-    let accepts = preferred_format(headers);
-    for af in accepts {
-        use AcceptFormat::*;
-        match af {
-            PartialHtml => return wrapped_file(path, context).await,
-            _ => continue,
-        }
+pub async fn file(path: &Path, headers: &HeaderMap, context: &ServerContext) -> Result<Response<Body>> {
+    if headers.contains_key("x-partial") {
+        return wrapped_file(path, headers, context).await;
+    }
+    // Files can only be sent as they are, or described in JSON
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+    let available = [mime.essence_str(), AcceptFormat::Json.media_type()];
+    match accept::negotiate(accept_header(headers).as_deref(), &available) {
+        Some(0) => response::send_file(path, headers).await,
+        Some(_) => file_json(path, headers, context).await,
+        None => Ok(response::not_acceptable(&available)),
+    }
+}
+
+/// What is known about a file, for the JSON representation
+#[derive(Debug, Serialize)]
+struct FileInfo {
+    /// URL path of the file
+    path: String,
+    name: String,
+    media_type: Option<String>,
+    size: u64,
+    /// Seconds since the unix epoch
+    modified: Option<u64>,
+}
+
+impl FileInfo {
+    async fn new(path: &Path, context: &ServerContext) -> std::io::Result<Self> {
+        let metadata = fs::metadata(path).await?;
+        Ok(Self {
+            path: context.strip_path(path)
+                .map(|p| p.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            name: path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default(),
+            media_type: mime_guess::from_path(path).first().map(|m| m.essence_str().to_string()),
+            size: metadata.len(),
+            modified: metadata.modified().ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs()),
+        })
     }
-    response::send_file(path).await
 }
 
-async fn wrapped_file(path: &Path, _context: &ServerContext) -> Response<Body> {
-    let stripped = _context.strip_path(path);
-    if stripped.is_none() {
-        return response::not_found();
+async fn file_json(path: &Path, headers: &HeaderMap, context: &ServerContext) -> Result<Response<Body>> {
+    let validators = Validators::for_file(path).await?.derive("json", &[]);
+    conditional::respond(headers, &validators, async {
+        let info = FileInfo::new(path, context).await?;
+        Ok(response::send_json(&info))
+    }).await
+}
+
+async fn wrapped_file(path: &Path, headers: &HeaderMap, _context: &ServerContext) -> Result<Response<Body>> {
+    let stripped = _context.strip_path(path).ok_or(Error::NotFound)?;
+    let validators = Validators::for_file(path).await?.derive("partial", &[]);
+    if validators.not_modified(headers) {
+        return Ok(conditional::not_modified(&validators));
     }
-    let stripped = stripped.unwrap();
     let guess = mime_guess::from_path(path).first();
-    match guess {
+    let mut resp = match guess {
         Some(mime) => {
             match mime.type_().as_str() {
                 "image" => {
@@ -89,117 +182,367 @@ async fn wrapped_file(path: &Path, _context: &ServerContext) -> Response<Body> {
 
                     }
                 },
-                _ => response::send_file(path).await,
+                // The file itself is sent, which handles its own validators
+                _ => return response::send_file(path, headers).await,
             }
 
         },
-        None => response::send_file(path).await,
-    }
+        None => return response::send_file(path, headers).await,
+    };
+    validators.apply(resp.headers_mut());
+    Ok(resp)
 }
 
 // }}}
 
 // Markdown handlers {{{
-pub async fn markdown(path: &Path, headers: &HeaderMap, context: &ServerContext) -> Response<Body> {
+
+/// A note, in whichever format the request prefers
+///
+/// HEAD requests get the same response, which is rendered (or taken from the cache) so that its
+/// headers match, and have the body dropped afterwards.
+pub async fn markdown(path: &Path, headers: &HeaderMap, context: &ServerContext) -> Result<Response<Body>> {
     #[cfg(debug_assertions)]
-    context.reload_templates();
-
-    let accepts = preferred_format(headers);
-    for af in accepts {
-        use AcceptFormat::*;
-        match af {
-            PartialHtml => return naked_markdown(path).await,
-            Html | Any  => return full_markdown(path, context).await,
-            _ => continue,
-        }
+    context.reload_templates()?;
+
+    let validators = Validators::for_file(path).await?;
+    use AcceptFormat::*;
+    let format = preferred_format(headers, &PAGE_FORMATS);
+    if matches!(format, Some(PartialHtml | Html | Json)) {
+        // NOTE: wiki links are resolved against the tree, and the full page also holds
+        // the navigation tree, so the tree has to be refreshed before it can be part of the entity
+        // tag. Any change to the tree can change the page, so the note's modification time can't
+        // be used.
+        context.refresh_roottree().await;
     }
-    response::not_acceptable()
+    // Backlinks come from other notes, which can change without the tree changing
+    let backlinks = fingerprint(&context.backlinks(path));
+    let rendered = validators.clone().without_last_modified();
+    let state = [context.renderer.state(), context.tree_state()];
+    let derived = |format| match format {
+        PartialHtml => rendered.derive("partial", &state),
+        Html => rendered.derive("full", &[state[0], context.template_state(), state[1], backlinks]),
+        _ => rendered.derive("json", &[state[0], state[1], backlinks]),
+    };
+    match format {
+        Some(PartialHtml) => {
+            return conditional::respond(headers, &derived(PartialHtml), naked_markdown(path, context)).await;
+        },
+        Some(Html) => {
+            return conditional::respond(headers, &derived(Html), full_markdown(path, context)).await;
+        },
+        Some(Json) => {
+            return conditional::respond(headers, &derived(Json), json_markdown(path, context)).await;
+        },
+        Some(format @ (Markdown | Plain)) => {
+            // The source is the file itself, so the file's modification time still applies, but
+            // each media type needs its own tag
+            let validators = validators.derive(format.media_type(), &[]);
+            return conditional::respond(headers, &validators, source_markdown(path, format)).await;
+        },
+        None => Ok(response::not_acceptable(&media_types(&PAGE_FORMATS))),
+    }
+}
+
+/// The markdown source, as `text/markdown` or `text/plain`
+async fn source_markdown(path: &Path, format: AcceptFormat) -> Result<Response<Body>> {
+    let source = fs::read_to_string(path).await?;
+    Ok(response::send_text(source, format.media_type()))
 }
 
+/// The JSON representation of a note
+#[derive(Debug, Serialize)]
+struct NoteJson {
+    #[serde(flatten)]
+    file: FileInfo,
+    #[serde(flatten)]
+    note: Note,
+    backlinks: Vec<Backlink>,
+}
 
-async fn naked_markdown(path: &Path) -> Response<Body> {
-    let contents = parse_markdown(path).await;
-    match contents {
-        Ok(contents) => {
-            return response::send_html(contents);
-        },
-        Err(_) => {
-            //  TODO: better error handling of file errors
-            return response::not_found();
-        },
+async fn json_markdown(path: &Path, context: &ServerContext) -> Result<Response<Body>> {
+    let note = context.render_note(path).await?;
+    let file = FileInfo::new(path, context).await?;
+    let backlinks = context.backlinks(path);
+    let incomplete = note.incomplete;
+    Ok(no_store_if(incomplete, response::send_json(&NoteJson { file, note, backlinks })))
+}
+
+
+async fn naked_markdown(path: &Path, context: &ServerContext) -> Result<Response<Body>> {
+    let contents = context.render_note(path).await?;
+    Ok(no_store_if(contents.incomplete, response::send_html(contents.html)))
+}
+
+async fn full_markdown(path: &Path, context: &ServerContext) -> Result<Response<Body>> {
+    let note = context.render_note(path).await?;
+    let backlinks = context.backlinks(path);
+    let dirtree = context.roottree.read().expect("Could not read web root");
+    let tera = context.tera.read().unwrap();
+    let mut context = page_context(context);
+    context.insert("content", &note.html);
+    context.insert("meta", &note.meta);
+    context.insert("tags", &index::tag_links(&note.meta.tags));
+    context.insert("toc", &note.toc);
+    context.insert("backlinks", &backlinks);
+    context.insert("dirtree", &dirtree.deref());
+    let html_out = tera.render(MARKDOWN_TEMPLATE, &context)?;
+    Ok(no_store_if(note.incomplete, response::send_html(html_out)))
+}
+
+/// Keeps a note that was only partly rendered out of caches, so that it's rendered again
+fn no_store_if(incomplete: bool, mut resp: Response<Body>) -> Response<Body> {
+    if incomplete {
+        resp.headers_mut().insert("Cache-Control", HeaderValue::from_static("no-store"));
     }
+    resp
 }
 
-async fn full_markdown(path: &Path, context: &ServerContext) -> Response<Body> {
-    let contents = match parse_markdown(path).await {
-        Ok(contents) => {
-            contents
+// }}}
+
+// Tag pages {{{
+
+/// Lists all tags, or the notes with one tag
+///
+/// The index is refreshed first, since the pages are made from nothing else.
+pub async fn tags(tag: Option<&str>, headers: &HeaderMap, context: &ServerContext) -> Result<Response<Body>> {
+    use AcceptFormat::*;
+    let format = match preferred_format(headers, &TAG_FORMATS) {
+        Some(format) => format,
+        None => return Ok(response::not_acceptable(&media_types(&TAG_FORMATS))),
+    };
+    context.refresh_roottree().await;
+    let index = context.index.read().expect("could not read note index");
+    let mut tera_context = page_context(context);
+    let (template, state) = match tag {
+        Some(tag) => {
+            let page = index.tag(tag).ok_or(Error::NotFound)?;
+            if format == Json {
+                return Ok(tags_json(&page, headers));
+            }
+            tera_context.insert("tag", &page);
+            let template = if format == PartialHtml { TAG_CHUNK_TEMPLATE } else { TAG_TEMPLATE };
+            (template, fingerprint(&page))
         },
-        Err(_) => {
-            //  TODO: better error handling of file errors
-            return response::not_found();
+        None => {
+            let tags = index.tags();
+            if format == Json {
+                return Ok(tags_json(&tags, headers));
+            }
+            tera_context.insert("tags", &tags);
+            let template = if format == PartialHtml { TAGS_CHUNK_TEMPLATE } else { TAGS_TEMPLATE };
+            (template, fingerprint(&tags))
         },
     };
-    // Reload roottree
-    context.refresh_roottree();
-    let dirtree = context.roottree.read().expect("Could not read web root");
-    let tera = context.tera.read().unwrap();
-    let mut context = tera::Context::new();
-    context.insert("content", &contents);
-    context.insert("dirtree", &dirtree.deref());
-    match tera.render(MARKDOWN_TEMPLATE, &context) {
-        Ok(html_out) => {
-            response::send_html(html_out)
+    drop(index);
+    let validators = if format == PartialHtml {
+        Validators::from_fingerprint("partial-tags", &[state, context.template_state()])
+    } else {
+        Validators::from_fingerprint("tags", &[state, context.template_state(), context.tree_state()])
+    };
+    if validators.not_modified(headers) {
+        return Ok(conditional::not_modified(&validators));
+    }
+    let tera = context.tera.read().expect("could not read template engine");
+    if format != PartialHtml {
+        let root_tree = context.roottree.read().expect("could not read web-root tree");
+        tera_context.insert("dirtree", &root_tree.deref());
+    }
+    let mut resp = response::send_html(tera.render(template, &tera_context)?);
+    validators.apply(resp.headers_mut());
+    Ok(resp)
+}
+
+fn tags_json<T: Serialize + std::hash::Hash>(page: &T, headers: &HeaderMap) -> Response<Body> {
+    let validators = Validators::from_fingerprint("json-tags", &[fingerprint(page)]);
+    if validators.not_modified(headers) {
+        return conditional::not_modified(&validators);
+    }
+    let mut resp = response::send_json(page);
+    validators.apply(resp.headers_mut());
+    resp
+}
+
+// }}}
+
+// Highlighting stylesheet {{{
+
+/// The stylesheet of the highlighting theme
+pub fn highlight_css(headers: &HeaderMap, context: &ServerContext) -> Result<Response<Body>> {
+    let css = context.renderer.highlighter().ok_or(Error::NotFound)?.css();
+    let validators = Validators::from_fingerprint("highlight-css", &[fingerprint(&css)]);
+    if validators.not_modified(headers) {
+        return Ok(conditional::not_modified(&validators));
+    }
+    let mut resp = response::send_text(css.to_string(), "text/css");
+    validators.apply(resp.headers_mut());
+    Ok(resp)
+}
+
+// }}}
+
+// Reports {{{
+
+/// The broken links of every note, as text or JSON
+///
+/// The tree is refreshed first, and the report made again only if anything has changed.
+pub async fn link_report(headers: &HeaderMap, context: &ServerContext) -> Result<Response<Body>> {
+    let format = match preferred_format(headers, &REPORT_FORMATS) {
+        Some(format) => format,
+        None => return Ok(response::not_acceptable(&media_types(&REPORT_FORMATS))),
+    };
+    context.refresh_roottree().await;
+    let report = context.link_report().await;
+    Ok(match format {
+        AcceptFormat::Json => response::send_json(report.as_ref()),
+        _ => response::send_text(report.to_string(), AcceptFormat::Plain.media_type()),
+    })
+}
+
+/// Hits, misses and size of the render cache, as JSON
+pub fn cache_stats(context: &ServerContext) -> Response<Body> {
+    response::send_json(&context.cache.stats())
+}
+
+// }}}
+
+// Error pages {{{
+
+/// Responds to a failed request with an error page
+///
+/// Full pages are rendered with `<status>.html` (e.g. `404.html`) or else `error.html`, and
+/// partial requests with `error-chunk.html`. All of these templates are optional: without them, a
+/// plain html fragment is sent instead.
+pub fn error(err: &Error, uri_path: &str, headers: &HeaderMap, context: &ServerContext) -> Response<Body> {
+    if err.status().is_server_error() {
+        error!(path = uri_path, "{err}");
+    } else {
+        debug!(path = uri_path, "{err}");
+    }
+    let format = preferred_format(headers, &ERROR_FORMATS).unwrap_or(AcceptFormat::Html);
+    let path = url_escape::decode(uri_path);
+    let mut resp = match format {
+        AcceptFormat::Json => response::send_json(&ErrorJson {
+            status: err.status().as_u16(),
+            message: err.public_message(),
+        }),
+        _ => {
+            let partial = format == AcceptFormat::PartialHtml;
+            let body = render_error(err, &path, partial, context)
+                .unwrap_or_else(|| default_error(err, &path));
+            response::send_html(body)
         },
+    };
+    *resp.status_mut() = err.status();
+    resp
+}
+
+/// The JSON representation of an error
+#[derive(Debug, Serialize)]
+struct ErrorJson {
+    status: u16,
+    message: String,
+}
+
+fn render_error(err: &Error, path: &str, partial: bool, context: &ServerContext) -> Option<String> {
+    let status = err.status();
+    let names = if partial {
+        vec![ERROR_CHUNK_TEMPLATE.to_string()]
+    } else {
+        vec![format!("{}.html", status.as_u16()), ERROR_TEMPLATE.to_string()]
+    };
+    // NOTE: a poisoned lock means an earlier panic, so just fall back to the default
+    let tera = context.tera.read().ok()?;
+    let name = names.into_iter().find(|n| tera.get_template_names().any(|t| t == n))?;
+    let mut tera_context = page_context(context);
+    tera_context.insert("status", &status.as_u16());
+    tera_context.insert("reason", status.canonical_reason().unwrap_or(""));
+    tera_context.insert("message", &err.public_message());
+    tera_context.insert("path", path);
+    if !partial {
+        let dirtree = context.roottree.read().ok()?;
+        tera_context.insert("dirtree", &dirtree.deref());
+    }
+    match tera.render(&name, &tera_context) {
+        Ok(rendered) => Some(rendered),
         Err(e) => {
-            eprintln!("{e}");
-            response::server_error("Error in applying template")
-        }
+            error!("{}", Error::from(e));
+            None
+        },
     }
 }
 
-async fn parse_markdown(path: &Path) -> Result<String, tokio::io::Error> {
-    let contents = fs::read_to_string(path).await?;
-    // NOTE(jladan): disable smart punctuation for sake of latex
-    let options = Options::from_bits_truncate(0b1011110);
-    let parser = Parser::new_ext(&contents, options);
-    // TODO: Would there be any benefit to making this an async stream?
-    let mut html_out = String::new();
-    html::push_html(&mut html_out, parser);
-    return Ok(html_out);
+/// Error fragment for when there is no template, or it could not be rendered
+fn default_error(err: &Error, path: &str) -> String {
+    let status = err.status();
+    format!("<h1>{} {}</h1>\n<p>{}</p>\n<p><code>{}</code></p>\n",
+            status.as_u16(),
+            status.canonical_reason().unwrap_or(""),
+            tera::escape_html(&err.public_message()),
+            tera::escape_html(path))
 }
 
 // }}}
 
 // Determining accepted format {{{
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum AcceptFormat {
     Html,
     PartialHtml,
     Json,
-    Any,
-}
-
-fn preferred_format(headers: &HeaderMap) -> Vec<AcceptFormat> {
-    if let Some(value) = headers.get("x-partial") {
-        eprintln!("Partial header: {value:?}");
-        return vec![AcceptFormat::PartialHtml];
-    }
-    if let Some(value) = headers.get("accept") {
-        value.to_str().expect("accept header could not be converted to string?")
-            .split(',').filter_map(|e| {
-                if e.contains("json") {
-                    Some(AcceptFormat::Json)
-                } else if e.contains("html") {
-                    Some(AcceptFormat::Html)
-                } else if e.contains("*/*") {
-                    Some(AcceptFormat::Any)
-                } else {
-                    None
-                }
-            }).collect()
-    } else {
-        vec![AcceptFormat::Any]
+    Markdown,
+    Plain,
+}
+
+impl AcceptFormat {
+    fn media_type(&self) -> &'static str {
+        match self {
+            AcceptFormat::Html | AcceptFormat::PartialHtml => "text/html",
+            AcceptFormat::Json => "application/json",
+            AcceptFormat::Markdown => "text/markdown",
+            AcceptFormat::Plain => "text/plain",
+        }
+    }
+}
+
+/// Formats for directories and notes, in order of preference
+const PAGE_FORMATS: [AcceptFormat; 4] = [
+    AcceptFormat::Html,
+    AcceptFormat::Json,
+    AcceptFormat::Markdown,
+    AcceptFormat::Plain,
+];
+
+/// Formats for tag pages, which have no source to send
+const TAG_FORMATS: [AcceptFormat; 2] = [AcceptFormat::Html, AcceptFormat::Json];
+
+/// Formats for errors, which fall back to HTML when neither is acceptable
+const ERROR_FORMATS: [AcceptFormat; 2] = [AcceptFormat::Html, AcceptFormat::Json];
+
+/// Formats for the link report, which is not a page
+const REPORT_FORMATS: [AcceptFormat; 2] = [AcceptFormat::Plain, AcceptFormat::Json];
+
+/// Picks which of the `available` formats to respond with
+///
+/// The `x-partial` header always gets partial HTML. Returns `None` if none of the formats are
+/// acceptable.
+fn preferred_format(headers: &HeaderMap, available: &[AcceptFormat]) -> Option<AcceptFormat> {
+    if headers.contains_key("x-partial") {
+        return Some(AcceptFormat::PartialHtml);
     }
+    let media_types = media_types(available);
+    let format = accept::negotiate(accept_header(headers).as_deref(), &media_types).map(|i| available[i]);
+    trace!(?format, "negotiated format");
+    format
+}
+
+fn media_types(formats: &[AcceptFormat]) -> Vec<&'static str> {
+    formats.iter().map(AcceptFormat::media_type).collect()
+}
+
+/// The `Accept` header, which may hold non-ASCII bytes even though it shouldn't
+fn accept_header(headers: &HeaderMap) -> Option<String> {
+    headers.get("accept").map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
 }
 
 // }}}
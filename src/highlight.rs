@@ -0,0 +1,170 @@
+//! Syntax highlighting of fenced code blocks
+//!
+//! Code is highlighted with syntect while rendering, and marked up with CSS classes rather than
+//! inline styles. The stylesheet for the configured theme is served at [CSS_PATH], so templates
+//! only need to link to it.
+//!
+//! The theme is one of syntect's built-in themes (e.g. `InspiredGitHub` or `base16-ocean.dark`),
+//! or the path of a `.tmTheme` file. Extra languages can be added by putting `.sublime-syntax`
+//! files in `Config::syntax_dir`.
+
+use std::path::Path;
+
+use pulldown_cmark::{Event, Tag, CodeBlockKind, CowStr};
+use syntect::{
+    highlighting::{Theme, ThemeSet},
+    html::{ClassedHTMLGenerator, ClassStyle, css_for_theme_with_class_style},
+    parsing::SyntaxSet,
+    util::LinesWithEndings,
+};
+use tracing::warn;
+
+/// URL path of the theme's stylesheet
+pub const CSS_PATH: &str = "/_highlight.css";
+
+/// Used when the configured theme can't be loaded
+pub const DEFAULT_THEME: &str = "InspiredGitHub";
+
+/// Prefix of all classes in the highlighted html, so they don't clash with the page's own
+const CLASS_STYLE: ClassStyle = ClassStyle::SpacedPrefixed { prefix: "hl-" };
+
+pub struct Highlighter {
+    syntaxes: SyntaxSet,
+    css: String,
+}
+
+impl Highlighter {
+    /// Loads the syntax definitions and theme
+    ///
+    /// Problems with either are logged, and the defaults are used instead.
+    pub fn new(theme: &str, syntax_dir: Option<&Path>) -> Self {
+        let syntaxes = match syntax_dir {
+            Some(dir) => {
+                let mut builder = SyntaxSet::load_defaults_newlines().into_builder();
+                match builder.add_from_folder(dir, true) {
+                    Ok(()) => builder.build(),
+                    Err(e) => {
+                        warn!("could not load syntax definitions from {}: {e}", dir.display());
+                        SyntaxSet::load_defaults_newlines()
+                    },
+                }
+            },
+            None => SyntaxSet::load_defaults_newlines(),
+        };
+        let theme = load_theme(theme).unwrap_or_else(|e| {
+            warn!("{e}; using {DEFAULT_THEME}");
+            load_theme(DEFAULT_THEME).expect("the default theme is built in")
+        });
+        let css = css_for_theme_with_class_style(&theme, CLASS_STYLE).unwrap_or_else(|e| {
+            warn!("could not generate the highlighting stylesheet: {e}");
+            String::new()
+        });
+        Self { syntaxes, css }
+    }
+
+    /// The stylesheet for the theme
+    pub fn css(&self) -> &str {
+        &self.css
+    }
+
+    /// Highlights a block of code, if the language is known
+    pub fn highlight(&self, code: &str, lang: &str) -> Option<String> {
+        let syntax = self.syntaxes.find_syntax_by_token(lang)?;
+        let mut generator = ClassedHTMLGenerator::new_with_class_style(syntax, &self.syntaxes, CLASS_STYLE);
+        for line in LinesWithEndings::from(code) {
+            if let Err(e) = generator.parse_html_for_line_which_includes_newline(line) {
+                warn!("could not highlight {lang} code: {e}");
+                return None;
+            }
+        }
+        Some(format!(
+            "<pre class=\"hl-code\"><code class=\"language-{}\">{}</code></pre>\n",
+            class_name(lang),
+            generator.finalize(),
+        ))
+    }
+
+    /// Replaces fenced code blocks in a stream of markdown events with highlighted html
+    ///
+    /// Blocks in languages that aren't known are left as they are.
+    pub fn highlight_events<'a>(&self, events: impl Iterator<Item = Event<'a>>) -> Vec<Event<'a>> {
+        let mut out = Vec::new();
+        // The opening event and text of the fenced block being collected
+        let mut block: Option<(Event<'a>, String, String)> = None;
+        for event in events {
+            match (event, block.as_mut()) {
+                (Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))), None) => {
+                    let lang = info.split_whitespace().next().unwrap_or("").to_string();
+                    let start = Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info)));
+                    block = Some((start, lang, String::new()));
+                },
+                (Event::Text(text), Some((_, _, code))) => code.push_str(&text),
+                (end @ Event::End(Tag::CodeBlock(_)), Some(_)) => {
+                    let (start, lang, code) = block.take().expect("checked by the match");
+                    match self.highlight(&code, &lang) {
+                        Some(html) => out.push(Event::Html(CowStr::from(html))),
+                        None => out.extend([start, Event::Text(CowStr::from(code)), end]),
+                    }
+                },
+                (event, _) => out.push(event),
+            }
+        }
+        out
+    }
+}
+
+/// A theme by name, or from a `.tmTheme` file
+fn load_theme(theme: &str) -> Result<Theme, String> {
+    if theme.ends_with(".tmTheme") {
+        return ThemeSet::get_theme(theme).map_err(|e| format!("could not load theme {theme}: {e}"));
+    }
+    let mut themes = ThemeSet::load_defaults().themes;
+    themes.remove(theme).ok_or_else(|| format!("unknown highlighting theme '{theme}'"))
+}
+
+/// Keeps a language name safe to put in a class attribute
+pub(crate) fn class_name(lang: &str) -> String {
+    lang.chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '+' | '#'))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pulldown_cmark::{Parser, html};
+
+    fn render(source: &str, highlighter: &Highlighter) -> String {
+        let mut out = String::new();
+        html::push_html(&mut out, highlighter.highlight_events(Parser::new(source)).into_iter());
+        out
+    }
+
+    #[test]
+    fn highlights_known_languages() {
+        let highlighter = Highlighter::new(DEFAULT_THEME, None);
+        let out = render("```rust\nfn main() {}\n```\n", &highlighter);
+        assert!(out.starts_with("<pre class=\"hl-code\"><code class=\"language-rust\">"), "{out}");
+        assert!(out.contains("hl-"));
+        assert!(!out.contains("fn main() {}"));
+    }
+
+    #[test]
+    fn leaves_unknown_languages() {
+        let highlighter = Highlighter::new(DEFAULT_THEME, None);
+        assert_eq!(render("```nonsense\n<a> & b\n```\n", &highlighter),
+                   "<pre><code class=\"language-nonsense\">&lt;a&gt; &amp; b\n</code></pre>\n");
+        assert_eq!(render("    indented\n", &highlighter), "<pre><code>indented\n</code></pre>\n");
+    }
+
+    #[test]
+    fn falls_back_to_default_theme() {
+        let highlighter = Highlighter::new("no such theme", None);
+        assert!(highlighter.css().contains(".hl-code"));
+    }
+
+    #[test]
+    fn sanitizes_class_names() {
+        assert_eq!(class_name("c++\"><script>"), "c++script");
+    }
+}
@@ -0,0 +1,573 @@
+//! Index of the notes under the web root
+//!
+//! Built from the same walk as `ServerContext::roottree`, and refreshed with it. Only the metadata
+//! block of each note is read, and notes are only read again when their modification time
+//! changes.
+//!
+//! The index backs the tag pages under [TAGS_PREFIX]. Tags can be hierarchical, like
+//! `project/md-server`: a note with that tag is also listed under `project`.
+//!
+//! It also keeps the links between notes, both markdown and `[[wiki]]` links, so that each note
+//! can list its backlinks. Links are kept as they are written, and resolved again whenever a note
+//! changes, since a new note can be the target of a wiki link that didn't resolve before. Notes are
+//! parsed with the same extensions and math delimiters they're rendered with.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fs,
+    path::Path,
+    time::SystemTime,
+};
+
+use pulldown_cmark::{Parser, Options, Event, Tag};
+use serde::Serialize;
+use tracing::warn;
+
+use crate::{
+    conditional::fingerprint,
+    config::Config,
+    context::Directory,
+    markdown::{self, Extensions, HeadingReader},
+    math::{Extracted, Math},
+    metadata::{self, Metadata},
+    wikilink::{self, WikiLinks},
+};
+
+/// Characters of context kept before and after a link, in a backlink's snippet
+const SNIPPET_BEFORE: usize = 60;
+const SNIPPET_AFTER: usize = 100;
+
+/// URL path under which the tag pages are served, instead of the web root
+pub const TAGS_PREFIX: &str = "/_tags/";
+
+#[derive(Debug, Clone, Default)]
+pub struct NoteIndex {
+    /// Notes by their URL path, e.g. `/notes/usage.md`
+    notes: HashMap<String, IndexedNote>,
+    /// Notes linking to each note, by URL path
+    backlinks: HashMap<String, Vec<Backlink>>,
+    /// Fingerprint of the notes' paths and modification times
+    state: u64,
+}
+
+#[derive(Debug, Clone)]
+struct IndexedNote {
+    modified: Option<SystemTime>,
+    meta: Metadata,
+    /// The metadata title, the first `# heading`, or else the file name
+    title: String,
+    links: Vec<OutLink>,
+}
+
+/// A link in a note, as it was written
+#[derive(Debug, Clone, PartialEq)]
+enum OutLink {
+    /// A markdown link's URL
+    Url { url: String, snippet: String },
+    /// The target of a `[[wiki link]]`
+    Wiki { target: String, snippet: String },
+}
+
+/// A note linking to another
+#[derive(Debug, Clone, Hash, Serialize)]
+pub struct Backlink {
+    pub path: String,
+    pub title: String,
+    /// Text around the (first) link
+    pub snippet: String,
+}
+
+/// A note, as listed on a tag page
+#[derive(Debug, Clone, Hash, Serialize)]
+pub struct NoteRef {
+    pub path: String,
+    pub title: String,
+    pub date: Option<String>,
+    pub tags: Vec<String>,
+}
+
+/// A tag, with its sub-tags
+#[derive(Debug, Clone, Hash, Serialize)]
+pub struct TagSummary {
+    /// Last part of the tag, e.g. `md-server`
+    pub name: String,
+    /// The whole tag, e.g. `project/md-server`
+    pub tag: String,
+    /// URL path of the tag's page
+    pub url: String,
+    /// Notes with exactly this tag
+    pub count: usize,
+    /// Notes with this tag or any of its sub-tags
+    pub total: usize,
+    pub children: Vec<TagSummary>,
+}
+
+/// A tag as a note has it, with the URL of its page
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TagLink {
+    /// The tag, tidied up, e.g. `project/md-server`
+    pub tag: String,
+    /// URL path of the tag's page
+    pub url: String,
+}
+
+/// Everything on the page of one tag
+#[derive(Debug, Clone, Hash, Serialize)]
+pub struct TagPage {
+    #[serde(flatten)]
+    pub tag: TagSummary,
+    /// Notes with this tag or any of its sub-tags, newest first
+    pub notes: Vec<NoteRef>,
+}
+
+impl NoteIndex {
+    /// Builds the index of every markdown file in `tree`
+    pub fn new(tree: &Directory, config: &Config) -> Self {
+        let mut index = Self::default();
+        index.refresh(tree, config);
+        index
+    }
+
+    /// Brings the index up to date with `tree`, reading only the notes that have changed
+    ///
+    /// `tree` must have been walked from `config.rootdir` with absolute paths.
+    pub fn refresh(&mut self, tree: &Directory, config: &Config) {
+        let rootdir = &config.rootdir;
+        let math = Math::new(&config.math_inline, &config.math_display);
+        let mut notes = HashMap::with_capacity(self.notes.len());
+        let mut changed = false;
+        for file in tree.all_files() {
+            if !file.path().ends_with(".md") {
+                continue;
+            }
+            let path = rootdir.join(file.path().trim_start_matches('/'));
+            let modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+            let note = match self.notes.remove(file.path()) {
+                Some(note) if note.modified.is_some() && note.modified == modified => note,
+                _ => {
+                    changed = true;
+                    read_note(&path, file.name(), modified, &config.extensions, math.as_ref())
+                },
+            };
+            notes.insert(file.path().to_string(), note);
+        }
+        // Anything left over has been removed
+        changed |= !self.notes.is_empty();
+        self.notes = notes;
+        if changed {
+            self.backlinks = self.link_graph(tree);
+            let modified: BTreeMap<&str, Option<SystemTime>> = self.notes.iter()
+                .map(|(path, note)| (path.as_str(), note.modified))
+                .collect();
+            self.state = fingerprint(&modified);
+        }
+    }
+
+    /// Fingerprint of the notes as of the last refresh, which changes when any note does
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+
+    /// Notes linking to the note at a URL path, by path
+    pub fn backlinks(&self, path: &str) -> Vec<Backlink> {
+        self.backlinks.get(path).cloned().unwrap_or_default()
+    }
+
+    /// Resolves every note's links, giving the backlinks of each note
+    fn link_graph(&self, tree: &Directory) -> HashMap<String, Vec<Backlink>> {
+        let mut wiki = WikiLinks::new(tree, "");
+        let mut graph: HashMap<String, Vec<Backlink>> = HashMap::new();
+        for (path, note) in &self.notes {
+            wiki.set_from(path);
+            let mut seen = HashSet::new();
+            for link in &note.links {
+                let (target, snippet) = match link {
+                    OutLink::Url { url, snippet } => (resolve_url(url, path), snippet),
+                    OutLink::Wiki { target, snippet } => (wiki.path(target).map(String::from), snippet),
+                };
+                let Some(target) = target else { continue };
+                if target == *path || !self.notes.contains_key(&target) || !seen.insert(target.clone()) {
+                    continue;
+                }
+                graph.entry(target).or_default().push(Backlink {
+                    path: path.clone(),
+                    title: note.title.clone(),
+                    snippet: snippet.clone(),
+                });
+            }
+        }
+        for backlinks in graph.values_mut() {
+            backlinks.sort_by(|a, b| a.path.cmp(&b.path));
+        }
+        graph
+    }
+
+    /// All tags, as a tree
+    pub fn tags(&self) -> Vec<TagSummary> {
+        let tagged = self.tagged();
+        let mut all: BTreeSet<&str> = BTreeSet::new();
+        for tag in tagged.keys() {
+            all.extend(ancestors(tag));
+        }
+        let roots: Vec<&str> = all.iter().copied().filter(|t| !t.contains('/')).collect();
+        roots.into_iter().map(|t| self.summary(t, &all, &tagged)).collect()
+    }
+
+    /// The page of one tag, if any note has it (or one of its sub-tags)
+    pub fn tag(&self, tag: &str) -> Option<TagPage> {
+        let tag = normalize_tag(tag)?;
+        let tagged = self.tagged();
+        let mut all: BTreeSet<&str> = BTreeSet::new();
+        for t in tagged.keys() {
+            all.extend(ancestors(t));
+        }
+        if !all.contains(tag.as_str()) {
+            return None;
+        }
+        let summary = self.summary(&tag, &all, &tagged);
+        let mut notes: Vec<NoteRef> = self.notes_under(&tag, &tagged).into_iter()
+            .map(|path| {
+                let note = &self.notes[path];
+                NoteRef {
+                    path: path.to_string(),
+                    title: note.title.clone(),
+                    date: note.meta.date.clone(),
+                    tags: note.meta.tags.clone(),
+                }
+            })
+            .collect();
+        // NOTE: dates are compared as written, which works for ISO dates
+        notes.sort_by(|a, b| b.date.cmp(&a.date).then_with(|| a.path.cmp(&b.path)));
+        Some(TagPage { tag: summary, notes })
+    }
+
+    /// Paths of the notes with each tag, exactly as written in the notes
+    fn tagged(&self) -> BTreeMap<String, BTreeSet<&str>> {
+        let mut tagged: BTreeMap<String, BTreeSet<&str>> = BTreeMap::new();
+        for (path, note) in &self.notes {
+            for tag in note.meta.tags.iter().filter_map(|t| normalize_tag(t)) {
+                tagged.entry(tag).or_default().insert(path);
+            }
+        }
+        tagged
+    }
+
+    fn notes_under<'a>(&self, tag: &str, tagged: &BTreeMap<String, BTreeSet<&'a str>>) -> BTreeSet<&'a str> {
+        let prefix = format!("{tag}/");
+        tagged.iter()
+            .filter(|(t, _)| *t == tag || t.starts_with(&prefix))
+            .flat_map(|(_, paths)| paths.iter().copied())
+            .collect()
+    }
+
+    fn summary(&self, tag: &str, all: &BTreeSet<&str>, tagged: &BTreeMap<String, BTreeSet<&str>>) -> TagSummary {
+        let prefix = format!("{tag}/");
+        let children = all.iter()
+            .filter(|t| t.strip_prefix(&prefix).is_some_and(|rest| !rest.contains('/')))
+            .map(|t| self.summary(t, all, tagged))
+            .collect();
+        TagSummary {
+            name: tag.rsplit('/').next().unwrap_or(tag).to_string(),
+            tag: tag.to_string(),
+            url: tag_url(tag),
+            count: tagged.get(tag).map(|paths| paths.len()).unwrap_or(0),
+            total: self.notes_under(tag, tagged).len(),
+            children,
+        }
+    }
+}
+
+fn read_note(path: &Path, name: &str, modified: Option<SystemTime>, extensions: &Extensions, math: Option<&Math>) -> IndexedNote {
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => {
+            warn!("could not index {}: {e}", path.display());
+            String::new()
+        },
+    };
+    let (meta, body) = match metadata::extract(&source) {
+        Some(block) => {
+            let body = format!("{}{}", &source[..block.range.start], &source[block.range.end..]);
+            (block.meta, body)
+        },
+        None => (Metadata::default(), source.clone()),
+    };
+    let options = extensions.for_note(&meta).options();
+    let links = read_links(&body, options, math);
+    let title = meta.title.clone()
+        .or_else(|| first_heading(&body, options, math))
+        .unwrap_or_else(|| name.trim_end_matches(".md").to_string());
+    IndexedNote { modified, meta, title, links }
+}
+
+/// The links in a note, with the text around them
+///
+/// Text is gathered a block (paragraph, list item, ...) at a time, so a link's snippet doesn't
+/// run into the next paragraph. Math is taken out first, as it is for rendering, so that it isn't
+/// read as markdown.
+fn read_links(source: &str, options: Options, math: Option<&Math>) -> Vec<OutLink> {
+    let math = match math {
+        Some(math) => math.extract(source),
+        None => Extracted::plain(source),
+    };
+    let mut links = Vec::new();
+    let mut block = Block::default();
+    let mut in_code = false;
+    for event in Parser::new_ext(&math.text, options) {
+        match event {
+            Event::Start(Tag::CodeBlock(_)) => in_code = true,
+            Event::End(Tag::CodeBlock(_)) => in_code = false,
+            _ if in_code => (),
+            Event::Text(text) => block.push_masked(&math.restore(&text), &math.blank(&text)),
+            Event::Code(text) => block.push(&text, true),
+            Event::SoftBreak | Event::HardBreak => block.push(" ", false),
+            Event::Start(Tag::Link(_, url, _)) => block.urls.push((block.text.len(), math.restore(&url).into_owned())),
+            Event::End(Tag::Paragraph | Tag::Heading(..) | Tag::Item | Tag::TableCell | Tag::BlockQuote) => {
+                block.finish(&mut links);
+            },
+            _ => (),
+        }
+    }
+    block.finish(&mut links);
+    links
+}
+
+/// Text of a block of markdown, and the links in it
+#[derive(Default)]
+struct Block {
+    text: String,
+    /// The text with code blanked out, to find wiki links in
+    masked: String,
+    /// Markdown links, by their position in the text
+    urls: Vec<(usize, String)>,
+}
+
+impl Block {
+    fn push(&mut self, text: &str, code: bool) {
+        if code {
+            self.push_masked(text, &" ".repeat(text.len()));
+        } else {
+            self.push_masked(text, text);
+        }
+    }
+
+    /// Adds text with parts of it (as long as they are) hidden from wiki links
+    fn push_masked(&mut self, text: &str, masked: &str) {
+        self.text.push_str(text);
+        self.masked.push_str(masked);
+    }
+
+    fn finish(&mut self, links: &mut Vec<OutLink>) {
+        let mut found: Vec<(usize, OutLink)> = self.urls.drain(..)
+            .map(|(at, url)| (at, OutLink::Url { url, snippet: snippet(&self.text, at) }))
+            .collect();
+        let mut from = 0;
+        while let Some((before, inner, after)) = wikilink::find_link(&self.masked[from..]) {
+            let at = from + before.len();
+            let target = inner.split('|').next().unwrap_or(inner).trim().to_string();
+            found.push((at, OutLink::Wiki { target, snippet: snippet(&self.text, at) }));
+            from = self.masked.len() - after.len();
+        }
+        found.sort_by_key(|(at, _)| *at);
+        links.extend(found.into_iter().map(|(_, link)| link));
+        self.text.clear();
+        self.masked.clear();
+    }
+}
+
+/// The text around position `at`, cut at spaces
+fn snippet(text: &str, at: usize) -> String {
+    let mut start = at.saturating_sub(SNIPPET_BEFORE);
+    while !text.is_char_boundary(start) {
+        start -= 1;
+    }
+    let mut end = (at + SNIPPET_AFTER).min(text.len());
+    while !text.is_char_boundary(end) {
+        end += 1;
+    }
+    if start > 0 {
+        start += text[start..at].find(' ').map_or(0, |i| i + 1);
+    }
+    if end < text.len() {
+        end = text[at..end].rfind(' ').map_or(end, |i| at + i);
+    }
+    let mut snippet = String::new();
+    if start > 0 {
+        snippet.push('…');
+    }
+    snippet.push_str(text[start..end].trim());
+    if end < text.len() {
+        snippet.push('…');
+    }
+    snippet
+}
+
+/// URL path of the file a markdown link points to, if it's in the web root
+fn resolve_url(url: &str, from: &str) -> Option<String> {
+    let external = markdown::is_external(url);
+    let url = url.split(['#', '?']).next().unwrap_or("");
+    if external || url.is_empty() {
+        return None;
+    }
+    let url = url_escape::decode(url);
+    let joined = match url.strip_prefix('/') {
+        Some(absolute) => absolute.to_string(),
+        None => format!("{}/{url}", from.rsplit_once('/').map(|(dir, _)| dir).unwrap_or("")),
+    };
+    let mut parts: Vec<&str> = Vec::new();
+    for part in joined.split('/') {
+        match part {
+            "" | "." => (),
+            ".." => { parts.pop(); },
+            part => parts.push(part),
+        }
+    }
+    Some(format!("/{}", parts.join("/")))
+}
+
+/// Text of the first top-level heading, read the same way as when the note is rendered
+fn first_heading(source: &str, options: Options, math: Option<&Math>) -> Option<String> {
+    let math = match math {
+        Some(math) => math.extract(source),
+        None => Extracted::plain(source),
+    };
+    let mut headings = HeadingReader::default();
+    for event in Parser::new_ext(&math.text, options) {
+        headings.read(&event, &math);
+    }
+    markdown::title(&headings.finish())
+}
+
+/// Tidies up a tag as written: `#project//md-server/` to `project/md-server`
+fn normalize_tag(tag: &str) -> Option<String> {
+    let parts: Vec<&str> = tag.trim().trim_start_matches('#')
+        .split('/')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .collect();
+    if parts.is_empty() { None } else { Some(parts.join("/")) }
+}
+
+/// URL path of the page of a tidied-up tag
+fn tag_url(tag: &str) -> String {
+    format!("{TAGS_PREFIX}{}", url_escape::encode_component(tag).replace("%2F", "/"))
+}
+
+/// A note's tags with their pages, leaving out any that are empty
+pub fn tag_links(tags: &[String]) -> Vec<TagLink> {
+    tags.iter()
+        .filter_map(|t| normalize_tag(t))
+        .map(|tag| TagLink { url: tag_url(&tag), tag })
+        .collect()
+}
+
+/// `a/b/c` to `a`, `a/b`, `a/b/c`
+fn ancestors(tag: &str) -> impl Iterator<Item = &str> {
+    tag.match_indices('/')
+        .map(move |(i, _)| &tag[..i])
+        .chain(std::iter::once(tag))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(notes: &[(&str, &[&str], Option<&str>)]) -> NoteIndex {
+        let notes = notes.iter().map(|(path, tags, date)| {
+            let meta = Metadata {
+                date: date.map(String::from),
+                tags: tags.iter().map(|t| t.to_string()).collect(),
+                ..Metadata::default()
+            };
+            (path.to_string(), IndexedNote { modified: None, meta, title: path.to_string(), links: Vec::new() })
+        });
+        NoteIndex { notes: notes.collect(), ..NoteIndex::default() }
+    }
+
+    #[test]
+    fn builds_tag_tree() {
+        let index = index(&[
+            ("/a.md", &["project/md-server", "rust"], None),
+            ("/b.md", &["project"], None),
+            ("/c.md", &["project/notes"], None),
+        ]);
+        let tags = index.tags();
+        assert_eq!(tags.iter().map(|t| t.tag.as_str()).collect::<Vec<_>>(), vec!["project", "rust"]);
+        let project = &tags[0];
+        assert_eq!((project.count, project.total), (1, 3));
+        assert_eq!(project.children.iter().map(|t| t.name.as_str()).collect::<Vec<_>>(),
+                   vec!["md-server", "notes"]);
+        assert_eq!(project.children[0].url, "/_tags/project/md-server");
+    }
+
+    #[test]
+    fn tag_page_includes_subtags() {
+        let index = index(&[
+            ("/a.md", &["project/md-server"], Some("2023-11-06")),
+            ("/b.md", &["project"], Some("2024-01-01")),
+            ("/c.md", &["other"], None),
+        ]);
+        let page = index.tag("project").unwrap();
+        assert_eq!(page.notes.iter().map(|n| n.path.as_str()).collect::<Vec<_>>(), vec!["/b.md", "/a.md"]);
+        let page = index.tag("project/md-server/").unwrap();
+        assert_eq!(page.notes.len(), 1);
+        assert!(index.tag("missing").is_none());
+        assert!(index.tag("proj").is_none());
+    }
+
+    #[test]
+    fn normalizes_tags() {
+        assert_eq!(normalize_tag(" #project//md-server/ ").as_deref(), Some("project/md-server"));
+        assert_eq!(normalize_tag("/"), None);
+        let links = tag_links(&["#c++ / 50%".to_string(), "/".to_string()]);
+        assert_eq!(links, vec![TagLink { tag: "c++/50%".to_string(), url: "/_tags/c%2B%2B/50%25".to_string() }]);
+        assert_eq!(ancestors("a/b/c").collect::<Vec<_>>(), vec!["a", "a/b", "a/b/c"]);
+    }
+
+    #[test]
+    fn titles_fall_back_to_heading() {
+        let options = Extensions::default().options();
+        let heading = |source| first_heading(source, options, None);
+        assert_eq!(heading("```toml\n# not a heading\n```\n# Usage\n## Other").as_deref(), Some("Usage"));
+        assert_eq!(heading("Setext *title*\n===\n\n# Later").as_deref(), Some("Setext title"));
+        assert_eq!(heading("# The `Config` type").as_deref(), Some("The Config type"));
+        assert_eq!(heading("## Only a section"), None);
+    }
+
+    #[test]
+    fn reads_links() {
+        let options = Extensions::default().options();
+        let links = read_links("See [usage](notes/usage.md) and [[Plan|the plan]].\n\n`[[code]]`\n\n```\n[[block]]\n```\n", options, None);
+        assert_eq!(links, vec![
+            OutLink::Url { url: "notes/usage.md".into(), snippet: "See usage and [[Plan|the plan]].".into() },
+            OutLink::Wiki { target: "Plan".into(), snippet: "See usage and [[Plan|the plan]].".into() },
+        ]);
+        let long = format!("{} [[x]] {}", "word ".repeat(20), "more ".repeat(30));
+        let OutLink::Wiki { snippet, .. } = &read_links(&long, options, None)[0] else { panic!() };
+        assert!(snippet.starts_with("…word") && snippet.ends_with("more…"), "{snippet}");
+    }
+
+    #[test]
+    fn reads_links_like_rendering() {
+        let config = Config::default();
+        let math = Math::new(&config.math_inline, &config.math_display);
+        let links = read_links("$$[[a]] [b](b.md)$$ and [[c]] [d](d.md)\n", config.extensions.options(), math.as_ref());
+        assert_eq!(links, vec![
+            OutLink::Wiki { target: "c".into(), snippet: "$$[[a]] [b](b.md)$$ and [[c]] d".into() },
+            OutLink::Url { url: "d.md".into(), snippet: "$$[[a]] [b](b.md)$$ and [[c]] d".into() },
+        ]);
+        let meta = Metadata { extra: [("extensions".to_string(), "-tables".into())].into(), ..Metadata::default() };
+        let options = config.extensions.for_note(&meta).options();
+        assert!(!options.contains(Options::ENABLE_TABLES));
+    }
+
+    #[test]
+    fn resolves_urls() {
+        assert_eq!(resolve_url("usage.md#part", "/notes/a.md").as_deref(), Some("/notes/usage.md"));
+        assert_eq!(resolve_url("../my%20note.md", "/notes/a.md").as_deref(), Some("/my note.md"));
+        assert_eq!(resolve_url("/b/./c.md?x", "/notes/a.md").as_deref(), Some("/b/c.md"));
+        assert_eq!(resolve_url("https://example.com/a.md", "/a.md"), None);
+        assert_eq!(resolve_url("//example.com/a.md", "/a.md"), None);
+        assert_eq!(resolve_url("#heading", "/a.md"), None);
+    }
+}
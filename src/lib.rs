@@ -2,5 +2,22 @@ pub mod context;
 pub mod config;
 pub mod uri;
 pub mod response;
+pub mod range;
+pub mod conditional;
+pub mod compress;
 pub mod handler;
+pub mod markdown;
+pub mod cache;
+pub mod highlight;
+pub mod external;
+pub mod math;
+pub mod wikilink;
+pub mod metadata;
+pub mod index;
+pub mod linkcheck;
+pub mod accept;
+pub mod error;
+pub mod filter;
 
+pub mod logging;
+pub mod tls;
@@ -0,0 +1,203 @@
+//! Finding broken links between notes
+//!
+//! Every note in the web root is rendered (so math and `[[wiki links]]` are handled just like when
+//! it's served), and each of its links and images is looked up with [uri::resolve], as a request
+//! for it would be. Links to `#anchors` must also match a heading id in the note they point to.
+//! Links with a scheme, like `https:` or `mailto:`, are not checked.
+//!
+//! The report is made by the `check-links` command, and served at [REPORT_PATH] when
+//! `config.diagnostics` is set. The server checks the notes it has rendered already, with
+//! [check_notes].
+
+use std::{collections::HashMap, fmt, fs, path::{Path, PathBuf}};
+
+use serde::Serialize;
+use tracing::warn;
+
+use crate::{
+    config::Config,
+    context::walk_dir,
+    error::Error,
+    markdown::{is_external, LinkKind, Note, Renderer},
+    uri::{self, Resolved},
+    wikilink::WikiLinks,
+};
+
+/// Where the report is served
+pub const REPORT_PATH: &str = "/_links";
+
+/// The broken links of every note
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    /// Number of notes checked
+    pub notes: usize,
+    /// Number of links checked, leaving out external ones
+    pub links: usize,
+    pub broken: Vec<BrokenLink>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BrokenLink {
+    /// URL path of the note with the link
+    pub note: String,
+    pub kind: LinkKind,
+    pub url: String,
+    pub problem: Problem,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Problem {
+    /// Nothing exists at the path
+    NotFound,
+    /// The path can't be served, e.g. it climbs out of the web root
+    Forbidden,
+    /// The note has no heading with the anchor's id
+    MissingAnchor,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::NotFound => write!(f, "not found"),
+            Problem::Forbidden => write!(f, "forbidden"),
+            Problem::MissingAnchor => write!(f, "no such heading"),
+        }
+    }
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.broken.is_empty()
+    }
+}
+
+impl fmt::Display for Report {
+    /// One line for each broken link, then a summary
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for link in &self.broken {
+            let kind = match link.kind {
+                LinkKind::Link => "link",
+                LinkKind::Image => "image",
+            };
+            writeln!(f, "{}: {kind} to {}: {}", link.note, link.url, link.problem)?;
+        }
+        writeln!(f, "{} broken of {} links in {} notes", self.broken.len(), self.links, self.notes)
+    }
+}
+
+/// Checks the links of every note in the web root
+pub fn check(config: &Config, renderer: &Renderer) -> Report {
+    let tree = match walk_dir(&config.rootdir, true, &config.filter, &config.rootdir) {
+        Ok(tree) => tree,
+        Err(e) => {
+            warn!("could not walk the web root: {e}");
+            return Report::default();
+        },
+    };
+    let mut wiki = WikiLinks::new(&tree, "");
+    let mut notes: HashMap<PathBuf, Note> = HashMap::new();
+    let mut order = Vec::new();
+    for file in tree.all_files().into_iter().filter(|f| f.path().ends_with(".md")) {
+        let path = config.rootdir.join(file.path().trim_start_matches('/'));
+        let source = match fs::read_to_string(&path) {
+            Ok(source) => source,
+            Err(e) => {
+                warn!("could not read {}: {e}", file.path());
+                continue;
+            },
+        };
+        wiki.set_from(file.path());
+        notes.insert(path.clone(), renderer.render(source, file.path(), &wiki));
+        order.push((file.path().to_string(), path));
+    }
+    check_notes(config, &order, &notes)
+}
+
+/// Checks the links of rendered notes
+///
+/// `order` holds the URL path and path of each note in `notes`, in the order to report them.
+pub fn check_notes(config: &Config, order: &[(String, PathBuf)], notes: &HashMap<PathBuf, Note>) -> Report {
+    let mut report = Report { notes: notes.len(), ..Report::default() };
+    for (url_path, path) in order {
+        for link in notes[path].links.iter().filter(|l| !is_external(&l.url)) {
+            report.links += 1;
+            let Some(problem) = check_link(&link.url, url_path, path, notes, config) else { continue };
+            report.broken.push(BrokenLink {
+                note: url_path.clone(),
+                kind: link.kind,
+                url: link.url.clone(),
+                problem,
+            });
+        }
+    }
+    report.broken.sort_by(|a, b| a.note.cmp(&b.note));
+    report
+}
+
+/// What's wrong with a link from the note at `from` (a URL path) or `from_path`, if anything
+fn check_link(url: &str, from: &str, from_path: &Path, notes: &HashMap<PathBuf, Note>, config: &Config) -> Option<Problem> {
+    let (url, anchor) = match url.split_once('#') {
+        Some((url, anchor)) => (url, Some(url_escape::decode(anchor))),
+        None => (url, None),
+    };
+    let url = url.split('?').next().unwrap_or_default();
+    let target = if url.is_empty() {
+        from_path.to_path_buf()
+    } else {
+        let url = url_escape::decode(url);
+        let absolute = match url.strip_prefix('/') {
+            Some(_) => url.to_string(),
+            None => format!("{}/{url}", from.rsplit_once('/').map(|(dir, _)| dir).unwrap_or_default()),
+        };
+        let uri = url_escape::encode_path(&absolute).parse::<hyper::Uri>().ok()?;
+        match uri::resolve(&uri, config) {
+            Ok(Resolved::Markdown(path)) => path,
+            Ok(_) => return None,
+            Err(Error::Forbidden) => return Some(Problem::Forbidden),
+            Err(_) => return Some(Problem::NotFound),
+        }
+    };
+    let anchor = anchor.filter(|a| !a.is_empty())?;
+    // NOTE: a note outside the tree, like one reached through a symlink, isn't checked
+    let note = notes.get(&target)?;
+    if note.headings.iter().any(|h| h.id == anchor) {
+        None
+    } else {
+        Some(Problem::MissingAnchor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_broken_links() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path();
+        fs::create_dir_all(base.join("sub")).unwrap();
+        fs::write(base.join("index.md"), concat!(
+            "# Home\n\n[ok](sub/page.md) [anchor](sub/page.md#part-one) [[page#Part One]]\n\n",
+            "[gone](missing.md) [bad anchor](sub/page.md#nope) [here](#home) [out](../../x.md)\n\n",
+            "![img](sub/pic.png) [web](https://example.com) <me@example.com>\n\n`[code](nothing.md)`\n",
+        )).unwrap();
+        fs::write(base.join("sub/page.md"), "## Part one\n\n[up](../index.md#missing)\n").unwrap();
+        let mut config = Config::builder();
+        config.set_root(base);
+        let report = check(&config.build(), &Renderer::default());
+
+        let broken: Vec<(&str, &str, Problem)> = report.broken.iter()
+            .map(|b| (b.note.as_str(), b.url.as_str(), b.problem))
+            .collect();
+        assert_eq!(broken, vec![
+            ("/index.md", "missing.md", Problem::NotFound),
+            ("/index.md", "sub/page.md#nope", Problem::MissingAnchor),
+            ("/index.md", "../../x.md", Problem::Forbidden),
+            ("/index.md", "sub/pic.png", Problem::NotFound),
+            ("/sub/page.md", "../index.md#missing", Problem::MissingAnchor),
+        ]);
+        assert_eq!((report.notes, report.links), (2, 9));
+        assert!(report.to_string().ends_with("5 broken of 9 links in 2 notes\n"));
+    }
+}
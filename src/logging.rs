@@ -0,0 +1,174 @@
+//! Logging setup and the access log
+//!
+//! Diagnostics go through `tracing`, filtered by `RUST_LOG` or the `--log-level` option. Each
+//! request gets a span (see `main::route`) carrying its method, path, resolved kind, status,
+//! bytes sent and latency.
+//!
+//! The access log is separate: when enabled with `Config::access_log`, one line per request is
+//! written to stdout in the Common or Combined Log Format, for tools that expect those.
+
+use std::{
+    fmt,
+    net::SocketAddr,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use hyper::{Method, Uri, Version, StatusCode, HeaderMap};
+use tracing_subscriber::EnvFilter;
+
+/// Default filter, when neither `--log-level` nor `RUST_LOG` is given
+const DEFAULT_FILTER: &str = "info";
+
+/// Installs the global `tracing` subscriber, logging to stderr
+///
+/// `level` is an env-filter directive, such as `debug` or `hyper_markdown_server=trace`, and takes
+/// precedence over `RUST_LOG`.
+pub fn init(level: Option<&str>) {
+    let filter = match level {
+        Some(level) => EnvFilter::try_new(level).unwrap_or_else(|e| {
+            eprintln!("invalid log level {level:?}: {e}");
+            EnvFilter::new(DEFAULT_FILTER)
+        }),
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER)),
+    };
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .init();
+}
+
+/// Format of the access log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    /// `host ident user [time] "request" status bytes`
+    Common,
+    /// Common, followed by `"referer" "user-agent"`
+    Combined,
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "common" => Ok(AccessLogFormat::Common),
+            "combined" => Ok(AccessLogFormat::Combined),
+            _ => Err(format!("unknown access log format '{s}' (expected common or combined)")),
+        }
+    }
+}
+
+impl fmt::Display for AccessLogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccessLogFormat::Common => write!(f, "common"),
+            AccessLogFormat::Combined => write!(f, "combined"),
+        }
+    }
+}
+
+/// What the access log needs to know about a request
+pub struct AccessEntry<'a> {
+    pub remote: Option<SocketAddr>,
+    pub time: SystemTime,
+    pub method: &'a Method,
+    pub uri: &'a Uri,
+    pub version: Version,
+    pub headers: &'a HeaderMap,
+    pub status: StatusCode,
+    pub bytes: Option<u64>,
+}
+
+impl AccessEntry<'_> {
+    /// Formats the entry as one line of the access log
+    pub fn format(&self, format: AccessLogFormat) -> String {
+        let host = self.remote.map(|a| a.ip().to_string()).unwrap_or_else(|| "-".to_string());
+        let bytes = self.bytes.map(|b| b.to_string()).unwrap_or_else(|| "-".to_string());
+        let mut line = format!("{host} - - [{}] \"{} {} {:?}\" {} {bytes}",
+                               clf_time(self.time),
+                               self.method,
+                               self.uri,
+                               self.version,
+                               self.status.as_u16());
+        if format == AccessLogFormat::Combined {
+            let header = |name: &str| {
+                self.headers.get(name)
+                    .map(|v| escape(&String::from_utf8_lossy(v.as_bytes())))
+                    .unwrap_or_else(|| "-".to_string())
+            };
+            line.push_str(&format!(" \"{}\" \"{}\"", header("referer"), header("user-agent")));
+        }
+        line
+    }
+}
+
+/// Quotes inside logged header values would break the format
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun",
+                            "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Formats a time as `10/Oct/2000:13:55:36 +0000`, always in UTC
+fn clf_time(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, rem) = (secs / 86400, secs % 86400);
+    let (year, month, day) = civil_from_days(days as i64);
+    format!("{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+            day, MONTHS[(month - 1) as usize], year, rem / 3600, (rem % 3600) / 60, rem % 60)
+}
+
+/// Converts days since the unix epoch to a (year, month, day) date
+///
+/// This is Howard Hinnant's `civil_from_days` algorithm.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use hyper::http::HeaderValue;
+
+    #[test]
+    fn formats_clf_time() {
+        let time = UNIX_EPOCH + Duration::from_secs(971_186_136);
+        assert_eq!(clf_time(time), "10/Oct/2000:13:55:36 +0000");
+        assert_eq!(clf_time(UNIX_EPOCH), "01/Jan/1970:00:00:00 +0000");
+        let leap = UNIX_EPOCH + Duration::from_secs(951_782_400);
+        assert_eq!(clf_time(leap), "29/Feb/2000:00:00:00 +0000");
+    }
+
+    #[test]
+    fn formats_combined_entry() {
+        let mut headers = HeaderMap::new();
+        headers.insert("user-agent", HeaderValue::from_static("curl/8.0 \"test\""));
+        let uri: Uri = "/notes/usage.md".parse().unwrap();
+        let entry = AccessEntry {
+            remote: Some(SocketAddr::from(([127, 0, 0, 1], 50000))),
+            time: UNIX_EPOCH + Duration::from_secs(971_186_136),
+            method: &Method::GET,
+            uri: &uri,
+            version: Version::HTTP_11,
+            headers: &headers,
+            status: StatusCode::OK,
+            bytes: Some(2326),
+        };
+        assert_eq!(entry.format(AccessLogFormat::Common),
+                   "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /notes/usage.md HTTP/1.1\" 200 2326");
+        assert_eq!(entry.format(AccessLogFormat::Combined),
+                   "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /notes/usage.md HTTP/1.1\" 200 2326 \"-\" \"curl/8.0 \\\"test\\\"\"");
+    }
+}
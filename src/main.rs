@@ -2,43 +2,80 @@ use std::convert::Infallible;
 use std::{
     sync::Arc,
     path::PathBuf,
-    net::ToSocketAddrs,
+    net::{SocketAddr, ToSocketAddrs},
+    time::{Instant, SystemTime},
 };
 
-use hyper::{Method, StatusCode, Body, Request, Response, Server};
+use hyper::{Method, StatusCode, Body, Request, Response, Server, http::HeaderValue};
+use hyper::body::HttpBody;
+use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
 
 use clap::Parser;
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, warn, info_span, field, Instrument, Span};
 
 use hyper_markdown_server::{
     context::ServerContext,
-    config::Config,
+    config::{Config, SymlinkPolicy, TlsConfig},
     uri,
     response,
     handler,
+    compress,
+    logging::{self, AccessEntry, AccessLogFormat},
+    linkcheck,
+    markdown::{Extensions, Renderer},
+    math::{Delimiters, MathMode},
+    tls,
 };
 
 #[tokio::main]
 async fn main() {
     // Load configuration
-    let cli = Cli::parse();
+    let mut cli = Cli::parse();
+    logging::init(cli.log_level.as_deref());
+    let command = cli.command.take();
     let config = make_config(cli);
-    eprintln!("{:#?}", config);
+    debug!("{:#?}", config);
+
+    if let Some(Command::CheckLinks { json }) = command {
+        std::process::exit(check_links(&config, json));
+    }
 
     // NOTE: addr has to be cloned before the config is moved into the services
     let addr = config.addr;
+    let tls = config.tls.clone();
+    let redirect_port = config.redirect_port;
     let context = Arc::new(ServerContext::new(config));
 
+    let result = match tls {
+        Some(tls) => serve_tls(addr, &tls, redirect_port, context).await,
+        None => {
+            if redirect_port.is_some() {
+                warn!("ignoring the HTTP redirect, since TLS is not configured");
+            }
+            serve(addr, context).await
+        },
+    };
+    if let Err(e) = result {
+        error!("server error: {}", e);
+    }
+}
+
+/// Serves plain HTTP
+async fn serve(addr: SocketAddr, context: Arc<ServerContext>) -> hyper::Result<()> {
     // A `Service` is needed for every connection.
     // This creates one from the `route` function.
-    let make_svc = make_service_fn(move |_conn| {
+    let make_svc = make_service_fn(move |conn: &AddrStream| {
         // NOTE: the state must be cloned before use in an async block
         // This clone is for the function which makes new services
         let context = context.clone();
+        let remote = conn.remote_addr();
         let service = service_fn(move |req| {
             // NOTE: the state must be cloned a second time
             // This clone is for the service itself
-            route(req, context.clone())
+            route(req, context.clone(), Some(remote))
         });
         async move {
             Ok::<_, Infallible>(service)
@@ -46,39 +83,165 @@ async fn main() {
     });
 
     let server = Server::bind(&addr).serve(make_svc);
-    let server = server.with_graceful_shutdown(shutdown_signal());
+    info!("listening on http://{addr}");
+    server.with_graceful_shutdown(shutdown_signal()).await
+}
+
+/// Serves HTTPS, and optionally redirects plain HTTP on `redirect_port` to it
+async fn serve_tls(addr: SocketAddr, tls: &TlsConfig, redirect_port: Option<u16>, context: Arc<ServerContext>) -> hyper::Result<()> {
+    let resolver = match tls::CertResolver::new(&tls.cert, &tls.key) {
+        Ok(resolver) => Arc::new(resolver),
+        Err(e) => {
+            error!("could not load the TLS certificate: {e}");
+            std::process::exit(1);
+        },
+    };
+    let server_config = tls::server_config(resolver.clone()).expect("invalid TLS configuration");
+    let acceptor = TlsAcceptor::from(Arc::new(server_config));
+    tokio::spawn(tls::watch(resolver));
+
+    // NOTE: same as in `serve`, but the connections come from rustls
+    let make_svc = make_service_fn(move |conn: &tls::TlsConn| {
+        let context = context.clone();
+        let remote = conn.remote_addr();
+        let service = service_fn(move |req| {
+            route(req, context.clone(), Some(remote))
+        });
+        async move {
+            Ok::<_, Infallible>(service)
+        }
+    });
+    let listener = TcpListener::bind(addr).await
+        .unwrap_or_else(|e| panic!("could not bind {addr}: {e}"));
+    let server = Server::builder(tls::incoming(listener, acceptor)).serve(make_svc);
+    info!("listening on https://{addr}");
 
-    if let Err(e) = server.await {
-        eprintln!("server error: {}", e);
+    if let Some(port) = redirect_port {
+        let redirect_addr = SocketAddr::new(addr.ip(), port);
+        let https_port = addr.port();
+        let make_redirect = make_service_fn(move |_conn: &AddrStream| async move {
+            Ok::<_, Infallible>(service_fn(move |req| async move {
+                Ok::<_, Infallible>(tls::redirect(&req, https_port))
+            }))
+        });
+        let redirect = Server::bind(&redirect_addr).serve(make_redirect);
+        info!("redirecting http://{redirect_addr} to https");
+        tokio::spawn(async move {
+            if let Err(e) = redirect.with_graceful_shutdown(shutdown_signal()).await {
+                error!("redirect server error: {}", e);
+            }
+        });
+    }
+    server.with_graceful_shutdown(shutdown_signal()).await
+}
+
+/// Handles a request inside its own span, and logs the outcome
+async fn route(req: Request<Body>, state: Arc<ServerContext>, remote: Option<SocketAddr>) -> Result<Response<Body>, Infallible> {
+    let start = Instant::now();
+    let time = SystemTime::now();
+    let span = info_span!("request",
+        method = %req.method(),
+        path = req.uri().path(),
+        kind = field::Empty,
+        status = field::Empty,
+        bytes = field::Empty,
+        latency_ms = field::Empty,
+    );
+    let resp = handle(&req, &state).instrument(span.clone()).await;
+
+    // NOTE: compressed bodies are streamed, so their size isn't known here. HEAD responses
+    // keep the Content-Length of the GET, but send nothing.
+    let bytes = match *req.method() {
+        Method::HEAD => None,
+        _ => resp.headers().get("content-length")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+            .or_else(|| resp.body().size_hint().exact()),
+    };
+    span.record("status", resp.status().as_u16());
+    if let Some(bytes) = bytes {
+        span.record("bytes", bytes);
+    }
+    span.record("latency_ms", start.elapsed().as_secs_f64() * 1000.0);
+    span.in_scope(|| info!("response sent"));
+
+    if let Some(format) = state.config.access_log {
+        let entry = AccessEntry {
+            remote,
+            time,
+            method: req.method(),
+            uri: req.uri(),
+            version: req.version(),
+            headers: req.headers(),
+            status: resp.status(),
+            bytes,
+        };
+        println!("{}", entry.format(format));
     }
+    Ok(resp)
 }
 
-async fn route(req: Request<Body>, state: Arc<ServerContext>) -> Result<Response<Body>, Infallible> {
+async fn handle(req: &Request<Body>, state: &Arc<ServerContext>) -> Response<Body> {
     let resolved = uri::resolve(req.uri(), &state.config);
-    eprintln!("{resolved:?}");
-    match (req.method(), resolved) {
-        (&Method::GET, Some(uri::Resolved::File(path))) => {
-            Ok(handler::file(&path, req.headers(), state.as_ref()).await)
+    if let Ok(resolved) = &resolved {
+        Span::current().record("kind", resolved.kind());
+    }
+    // NOTE: HEAD goes through the same handlers as GET, and the body is dropped afterwards so the
+    // headers (Content-Length in particular) match
+    let result = match (req.method(), resolved) {
+        (&Method::GET | &Method::HEAD, Ok(uri::Resolved::File(path))) => {
+            handler::file(&path, req.headers(), state.as_ref()).await
+        },
+        (&Method::GET | &Method::HEAD, Ok(uri::Resolved::Markdown(path))) => {
+            handler::markdown(&path, req.headers(), state.as_ref()).await
+        },
+        (&Method::GET | &Method::HEAD, Ok(uri::Resolved::Directory(path))) => {
+            handler::directory(&path, req.headers(), state.as_ref()).await
+        },
+        (&Method::GET | &Method::HEAD, Ok(uri::Resolved::Tags(tag))) => {
+            handler::tags(tag.as_deref(), req.headers(), state.as_ref()).await
         },
-        (&Method::GET, Some(uri::Resolved::Markdown(path))) => {
-            Ok(handler::markdown(&path, req.headers(), state.as_ref()).await)
+        (&Method::GET | &Method::HEAD, Ok(uri::Resolved::HighlightCss)) => {
+            handler::highlight_css(req.headers(), state.as_ref())
         },
-        (&Method::GET, Some(uri::Resolved::Directory(path))) => {
-            Ok(handler::directory(&path, req.headers(), state.as_ref()))
+        (&Method::GET | &Method::HEAD, Ok(uri::Resolved::LinkReport)) => {
+            handler::link_report(req.headers(), state.as_ref()).await
         },
-        (&Method::GET, None) => {
-            Ok(response::not_found())
+        (&Method::GET | &Method::HEAD, Ok(uri::Resolved::CacheStats)) => {
+            Ok(handler::cache_stats(state.as_ref()))
         },
-        (&Method::HEAD, _) => {
-            Ok(response::not_implemented())
+        (&Method::GET | &Method::HEAD, Err(e)) => {
+            Err(e)
         },
         _ => {
             Ok(Response::builder()
                 .status(StatusCode::METHOD_NOT_ALLOWED)
-                .body(Body::from("Only get requests are possible"))
+                .header("Allow", "GET, HEAD")
+                .body(Body::from("Only GET and HEAD requests are possible"))
                 .unwrap())
         },
+    };
+    let mut resp = result.unwrap_or_else(|e| {
+        handler::error(&e, req.uri().path(), req.headers(), state.as_ref())
+    });
+    // NOTE: every resource has a partial variant, and most have format variants
+    resp.headers_mut().append("Vary", HeaderValue::from_static("Accept, x-partial"));
+    let resp = compress::compress(resp, req.headers(), &state.config);
+    if req.method() == Method::HEAD {
+        return response::strip_body(resp);
+    }
+    resp
+}
+
+/// Prints the broken links of every note, giving the exit code
+fn check_links(config: &Config, json: bool) -> i32 {
+    let report = linkcheck::check(config, &Renderer::new(config));
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report).expect("the report can always be serialized"));
+    } else {
+        print!("{report}");
     }
+    if report.is_ok() { 0 } else { 1 }
 }
 
 async fn shutdown_signal() {
@@ -106,6 +269,89 @@ struct Cli {
     /// Sets the location of document templates
     #[arg(short, long, value_name = "TEMPLATE_DIR")]
     template_dir: Option<PathBuf>,
+    /// Which symbolic links to follow: deny, within-root (default) or all
+    #[arg(long, value_name = "POLICY")]
+    symlinks: Option<SymlinkPolicy>,
+    /// Patterns of paths to never serve (default: '.*')
+    #[arg(long, value_name = "PATTERNS", value_delimiter = ',')]
+    deny: Option<Vec<String>>,
+    /// Patterns of paths to serve even if they are denied, e.g. '.well-known'
+    #[arg(long, value_name = "PATTERNS", value_delimiter = ',')]
+    allow: Option<Vec<String>>,
+
+    /// Smallest response (in bytes) to compress
+    #[arg(long, value_name = "BYTES")]
+    compress_min_size: Option<u64>,
+    /// Media types to never compress, e.g. 'image/png,video/*'
+    #[arg(long, value_name = "TYPES", value_delimiter = ',')]
+    compress_skip: Option<Vec<String>>,
+
+    /// Theme for highlighting code: a built-in theme, a .tmTheme file, or 'none' (default: 'InspiredGitHub')
+    #[arg(long, value_name = "THEME")]
+    highlight_theme: Option<String>,
+    /// Directory of extra .sublime-syntax definitions for highlighting
+    #[arg(long, value_name = "DIR")]
+    syntax_dir: Option<PathBuf>,
+    /// Delimiters of inline math, e.g. '$,\( \)', or 'none' (default: '$$, \( \)')
+    #[arg(long, value_name = "DELIMITERS")]
+    math_inline: Option<Delimiters>,
+    /// Delimiters of display math, e.g. '$$,\[ \]', or 'none' (default: '\[ \]')
+    #[arg(long, value_name = "DELIMITERS")]
+    math_display: Option<Delimiters>,
+    /// Where math is typeset: client (default, by MathJax) or mathml (on the server)
+    #[arg(long, value_name = "MODE")]
+    math_mode: Option<MathMode>,
+    /// TOML file of LaTeX macros for rendering MathML, e.g. '"\\R" = "\\mathbb{R}"'
+    #[arg(long, value_name = "FILE")]
+    math_macros: Option<PathBuf>,
+
+    /// Markdown extensions, e.g. 'tables,footnotes,smart-punctuation', or 'none' (default: all but smart-punctuation)
+    #[arg(long, value_name = "NAMES")]
+    extensions: Option<Extensions>,
+    /// TOML file of commands that render code blocks to SVG or HTML, e.g. 'dot = "dot -Tsvg"'
+    #[arg(long, value_name = "FILE")]
+    renderers: Option<PathBuf>,
+    /// Seconds that a command rendering a code block may run for (default: 10)
+    #[arg(long, value_name = "SECONDS")]
+    renderer_timeout: Option<u64>,
+    /// Memory for rendered notes, in bytes, or 0 to render every request (default: 32 MiB)
+    #[arg(long, value_name = "BYTES")]
+    cache_size: Option<u64>,
+
+    /// Log filter, e.g. 'debug' or 'hyper_markdown_server=trace' (default: $RUST_LOG or 'info')
+    #[arg(long, value_name = "FILTER")]
+    log_level: Option<String>,
+    /// Write an access log to stdout: common or combined
+    #[arg(long, value_name = "FORMAT")]
+    access_log: Option<AccessLogFormat>,
+    /// Serve the broken-link report at /_links and cache statistics at /_cache
+    #[arg(long)]
+    diagnostics: bool,
+
+    /// Serve HTTPS with this certificate chain (PEM)
+    #[arg(long, value_name = "FILE", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// Private key of the certificate (PEM)
+    #[arg(long, value_name = "FILE", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// Also listen for plain HTTP on this port, redirecting to HTTPS
+    #[arg(long, value_name = "PORT")]
+    redirect_http: Option<u16>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Report links between notes that don't resolve, instead of serving them
+    ///
+    /// Exits with 1 if any links are broken.
+    CheckLinks {
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
 }
 
 fn make_config(cli: Cli) -> Config {
@@ -115,7 +361,7 @@ fn make_config(cli: Cli) -> Config {
         if let Some(addr) = addrs.next() {
             config.set_address(&addr);
         } else {
-            println!("unrecognized address: {}", cli.addr.unwrap());
+            error!("unrecognized address: {}", cli.addr.unwrap());
         }
     }
     if let Some(port) = cli.port {
@@ -130,6 +376,99 @@ fn make_config(cli: Cli) -> Config {
     if let Some(tdir) = cli.template_dir {
         config.set_template(&tdir);
     }
+    if let Some(policy) = cli.symlinks {
+        config.set_symlinks(policy);
+    }
+    if let Some(deny) = cli.deny {
+        config.set_deny(&deny);
+    }
+    if let Some(allow) = cli.allow {
+        config.set_allow(&allow);
+    }
+    if let Some(size) = cli.compress_min_size {
+        config.set_compress_min_size(size);
+    }
+    if let Some(skip) = cli.compress_skip {
+        config.set_compress_skip(&skip);
+    }
+    if let Some(theme) = cli.highlight_theme {
+        config.set_highlight_theme(&theme);
+    }
+    if let Some(dir) = cli.syntax_dir {
+        config.set_syntax_dir(&dir);
+    }
+    if let Some(delims) = cli.math_inline {
+        config.set_math_inline(&delims);
+    }
+    if let Some(delims) = cli.math_display {
+        config.set_math_display(&delims);
+    }
+    if let Some(mode) = cli.math_mode {
+        config.set_math_mode(mode);
+    }
+    if let Some(macros) = cli.math_macros {
+        config.set_math_macros(&macros);
+    }
+    if let Some(extensions) = cli.extensions {
+        config.set_extensions(extensions);
+    }
+    if let Some(renderers) = cli.renderers {
+        config.set_renderers(&renderers);
+    }
+    if let Some(timeout) = cli.renderer_timeout {
+        config.set_renderer_timeout(timeout);
+    }
+    if let Some(size) = cli.cache_size {
+        config.set_cache_size(size);
+    }
+    if let Some(format) = cli.access_log {
+        config.set_access_log(Some(format));
+    }
+    if cli.diagnostics {
+        config.set_diagnostics(true);
+    }
+    if let (Some(cert), Some(key)) = (cli.tls_cert, cli.tls_key) {
+        config.set_tls(&cert, &key);
+    }
+    if let Some(port) = cli.redirect_http {
+        config.set_redirect_port(port);
+    }
 
-    return config.build();
+    config.build()
 } 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[tokio::test]
+    async fn head_matches_get() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("note.md"), "# Note\n\nSome *text*\n").unwrap();
+        let mut config = Config::builder();
+        config.set_root(dir.path());
+        config.set_template(&Path::new(env!("CARGO_MANIFEST_DIR")).join("sample/templates"));
+        let state = Arc::new(ServerContext::new(config.build()));
+
+        for (accept, encoding) in [("text/html", ""), ("application/json", ""), ("text/html", "gzip")] {
+            let request = |method: Method| Request::builder()
+                .method(method)
+                .uri("/note.md")
+                .header("Accept", accept)
+                .header("Accept-Encoding", encoding)
+                .body(Body::empty())
+                .unwrap();
+            let get = handle(&request(Method::GET), &state).await;
+            let head = handle(&request(Method::HEAD), &state).await;
+            let mut head_headers = head.headers().clone();
+            let length = head_headers.remove("Content-Length");
+            assert_eq!(get.headers(), &head_headers, "{accept} {encoding}");
+            assert!(get.headers().contains_key("ETag"));
+            let body = hyper::body::to_bytes(get.into_body()).await.unwrap();
+            let body_len = (encoding.is_empty()).then(|| HeaderValue::from(body.len()));
+            assert_eq!(length, body_len, "{accept} {encoding}");
+            assert!(hyper::body::to_bytes(head.into_body()).await.unwrap().is_empty());
+        }
+    }
+}
@@ -0,0 +1,596 @@
+//! Markdown rendering
+//!
+//! Turns a note into HTML with pulldown-cmark, while collecting what else is known about the note
+//! (metadata, headings and outgoing links) for templates and the JSON representation.
+//!
+//! Headings are given slug ids, so sections can be linked to, and a paragraph of just `[TOC]` is
+//! replaced by a table of contents. `[[wiki links]]` are resolved with [WikiLinks].
+//!
+//! Which markdown [Extensions] are used is set in the config, and can be changed by a note with an
+//! `extensions` list in its metadata.
+//!
+//! Relative link and image URLs are made root-absolute, since a note loaded as a partial page is
+//! shown under whatever URL the browser was at before.
+
+use std::{borrow::Cow, collections::HashSet, fmt, str::FromStr, time::Duration};
+
+use pulldown_cmark::{Parser, Options, Event, Tag, LinkType, CowStr, html, escape::{escape_href, escape_html}};
+
+use serde::Serialize;
+use serde_json::Value;
+use tracing::warn;
+
+use crate::{
+    config::Config,
+    external::ExternalRenderers,
+    highlight::Highlighter,
+    conditional::fingerprint,
+    math::{Extracted, Math, MathMode},
+    metadata::{self, Metadata},
+    wikilink::WikiLinks,
+};
+
+/// A rendered note
+#[derive(Debug, Clone, Serialize)]
+pub struct Note {
+    pub html: String,
+    pub source: String,
+    pub meta: Metadata,
+    pub headings: Vec<Heading>,
+    /// The headings as a tree
+    pub toc: Vec<TocEntry>,
+    pub links: Vec<Link>,
+    /// Part of the note couldn't be rendered (an external command failed), so it shouldn't be
+    /// kept: rendering it again may work
+    #[serde(skip)]
+    pub incomplete: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Heading {
+    pub level: u32,
+    pub text: String,
+    /// Either given with `{#id}`, or made from the text
+    pub id: String,
+}
+
+/// A heading in the table of contents, with the headings under it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TocEntry {
+    pub level: u32,
+    pub text: String,
+    pub id: String,
+    pub children: Vec<TocEntry>,
+}
+
+/// The marker that is replaced by the table of contents, when it's a paragraph of its own
+const TOC_MARKER: &str = "[TOC]";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Link {
+    pub kind: LinkKind,
+    pub url: String,
+    pub title: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkKind {
+    Link,
+    Image,
+}
+
+/// Renders notes, with the options from the config
+///
+/// The default renderer has no syntax highlighting, and doesn't look for math.
+#[derive(Default)]
+pub struct Renderer {
+    extensions: Extensions,
+    external: Option<ExternalRenderers>,
+    highlighter: Option<Highlighter>,
+    math: Option<Math>,
+    /// Fingerprint of the options, which change the html of every note
+    state: u64,
+}
+
+impl Renderer {
+    pub fn new(config: &Config) -> Self {
+        let highlighter = match config.highlight_theme.as_str() {
+            "none" => None,
+            theme => Some(Highlighter::new(theme, config.syntax_dir.as_deref())),
+        };
+        let math = Math::new(&config.math_inline, &config.math_display).map(|math| match config.math_mode {
+            MathMode::Client => math,
+            MathMode::Mathml => math.with_mathml(config.math_macros.as_deref()),
+        });
+        let external = config.renderers.as_deref()
+            .map(|path| ExternalRenderers::new(path, Duration::from_secs(config.renderer_timeout)));
+        // NOTE: changes to the macros and renderers files need a restart, like the rest of
+        // the config
+        let state = fingerprint(&(
+            config.extensions,
+            &config.renderers,
+            config.renderer_timeout,
+            &config.highlight_theme,
+            &config.syntax_dir,
+            &config.math_inline,
+            &config.math_display,
+            config.math_mode,
+            &config.math_macros,
+        ));
+        Self { extensions: config.extensions, external, highlighter, math, state }
+    }
+
+    pub fn highlighter(&self) -> Option<&Highlighter> {
+        self.highlighter.as_ref()
+    }
+
+    /// Fingerprint of the rendering options, for entity tags of rendered notes
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+
+    /// Render markdown source into a [Note]
+    ///
+    /// A metadata block at the top is left out of the html. Without a title in the metadata, the
+    /// first top-level heading is used. Relative URLs are resolved against `url_path`, the note's
+    /// own URL, but [Note::links] keeps them as they were written.
+    pub fn render(&self, source: String, url_path: &str, wiki: &WikiLinks) -> Note {
+        let (mut meta, body) = match metadata::extract(&source) {
+            Some(block) => {
+                let body = format!("{}{}", &source[..block.range.start], &source[block.range.end..]);
+                (block.meta, Cow::Owned(body))
+            },
+            None => (Metadata::default(), Cow::Borrowed(source.as_str())),
+        };
+        let math = match &self.math {
+            Some(math) => math.extract(&body),
+            None => Extracted::plain(&body),
+        };
+        let options = self.extensions.for_note(&meta).options();
+        let mut headings = HeadingReader::default();
+        let mut links = Vec::new();
+        let parser = wiki.events(Parser::new_ext(&math.text, options)).into_iter().inspect(|event| {
+            headings.read(event, &math);
+            match event {
+                Event::Start(Tag::Link(link_type, url, title)) => {
+                    // NOTE: email autolinks are given without the scheme they're rendered with
+                    let scheme = if *link_type == LinkType::Email { "mailto:" } else { "" };
+                    links.push(Link {
+                        kind: LinkKind::Link,
+                        url: format!("{scheme}{}", math.restore(url)),
+                        title: math.restore(title).into_owned(),
+                    });
+                },
+                Event::Start(Tag::Image(_, url, title)) => {
+                    links.push(Link {
+                        kind: LinkKind::Image,
+                        url: math.restore(url).into_owned(),
+                        title: math.restore(title).into_owned(),
+                    });
+                },
+                _ => (),
+            }
+        });
+        let events = absolute_urls(math.events(parser), url_path);
+        let mut headings = headings.finish();
+        assign_ids(&mut headings);
+        let toc = toc(&headings);
+        let mut events = with_toc(with_heading_ids(events, &headings), &toc);
+        let mut incomplete = false;
+        if let Some(external) = &self.external {
+            (events, incomplete) = external.render_events(events.into_iter());
+        }
+        // TODO: Would there be any benefit to making this an async stream?
+        let mut html_out = String::new();
+        match &self.highlighter {
+            Some(highlighter) => html::push_html(&mut html_out, highlighter.highlight_events(events.into_iter()).into_iter()),
+            None => html::push_html(&mut html_out, events.into_iter()),
+        }
+        if meta.title.is_none() {
+            meta.title = title(&headings);
+        }
+        Note { html: html_out, source, meta, headings, toc, links, incomplete }
+    }
+}
+
+// Extensions {{{
+
+/// All the extensions but smart punctuation, which would turn the quotes and dashes of LaTeX into
+/// typographic ones
+pub const DEFAULT_EXTENSIONS: &str = "tables, footnotes, strikethrough, tasklists, heading-attributes";
+
+/// The metadata key that changes the extensions of a note
+const EXTENSIONS_KEY: &str = "extensions";
+
+const EXTENSION_NAMES: [(&str, Options); 6] = [
+    ("tables", Options::ENABLE_TABLES),
+    ("footnotes", Options::ENABLE_FOOTNOTES),
+    ("strikethrough", Options::ENABLE_STRIKETHROUGH),
+    ("tasklists", Options::ENABLE_TASKLISTS),
+    ("smart-punctuation", Options::ENABLE_SMART_PUNCTUATION),
+    ("heading-attributes", Options::ENABLE_HEADING_ATTRIBUTES),
+];
+
+/// The markdown extensions that notes are parsed with
+///
+/// Written as a comma-separated list of names, like `tables, footnotes`, or `none`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Extensions(Options);
+
+impl Extensions {
+    pub fn options(&self) -> Options {
+        self.0
+    }
+
+    /// Turns extensions on or off: `name` turns one on, and `-name` turns it off
+    pub fn with_changes<'a>(mut self, changes: impl IntoIterator<Item = &'a str>) -> Result<Self, String> {
+        for change in changes.into_iter().map(str::trim).filter(|c| !c.is_empty()) {
+            let (on, name) = match change.strip_prefix('-') {
+                Some(name) => (false, name),
+                None => (true, change.strip_prefix('+').unwrap_or(change)),
+            };
+            let (_, option) = EXTENSION_NAMES.iter()
+                .find(|(known, _)| known.eq_ignore_ascii_case(name))
+                .ok_or_else(|| format!("unknown extension {name:?}"))?;
+            self.0.set(*option, on);
+        }
+        Ok(self)
+    }
+
+    /// The extensions for a note, changed by the `extensions` in its metadata
+    ///
+    /// The metadata can give a list like `["smart-punctuation", "-tables"]`, or the same as one
+    /// comma-separated string.
+    pub(crate) fn for_note(&self, meta: &Metadata) -> Self {
+        let changes: Vec<&str> = match meta.extra.get(EXTENSIONS_KEY) {
+            None => return *self,
+            Some(Value::String(list)) => list.split(',').collect(),
+            Some(Value::Array(list)) => list.iter().filter_map(Value::as_str).collect(),
+            Some(other) => {
+                warn!("invalid {EXTENSIONS_KEY} in metadata: {other}");
+                return *self;
+            },
+        };
+        self.with_changes(changes).unwrap_or_else(|e| {
+            warn!("invalid {EXTENSIONS_KEY} in metadata: {e}");
+            *self
+        })
+    }
+}
+
+impl Default for Extensions {
+    fn default() -> Self {
+        DEFAULT_EXTENSIONS.parse().expect("the default extensions are valid")
+    }
+}
+
+impl FromStr for Extensions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim().eq_ignore_ascii_case("none") {
+            return Ok(Extensions(Options::empty()));
+        }
+        Extensions(Options::empty()).with_changes(s.split(','))
+    }
+}
+
+impl fmt::Display for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = EXTENSION_NAMES.iter()
+            .filter(|(_, option)| self.0.contains(*option))
+            .map(|(name, _)| *name)
+            .collect();
+        if names.is_empty() {
+            return write!(f, "none");
+        }
+        write!(f, "{}", names.join(", "))
+    }
+}
+
+// }}}
+
+// Links {{{
+
+/// Links with a scheme, like `https:` or `mailto:`, or to another host
+pub(crate) fn is_external(url: &str) -> bool {
+    url.starts_with("//") || url.split_once(':').is_some_and(|(scheme, _)| !scheme.contains(['/', '#', '?']))
+}
+
+/// Makes the URLs of links and images root-absolute, for the note at `url_path`
+fn absolute_urls<'a>(events: Vec<Event<'a>>, url_path: &str) -> Vec<Event<'a>> {
+    let absolute = |url: CowStr<'a>| match absolute_url(&url, url_path) {
+        Some(absolute) => CowStr::from(absolute),
+        None => url,
+    };
+    events.into_iter()
+        .map(|event| match event {
+            Event::Start(Tag::Link(link_type, url, title)) if link_type != LinkType::Email => {
+                Event::Start(Tag::Link(link_type, absolute(url), title))
+            },
+            Event::End(Tag::Link(link_type, url, title)) if link_type != LinkType::Email => {
+                Event::End(Tag::Link(link_type, absolute(url), title))
+            },
+            Event::Start(Tag::Image(link_type, url, title)) => Event::Start(Tag::Image(link_type, absolute(url), title)),
+            Event::End(Tag::Image(link_type, url, title)) => Event::End(Tag::Image(link_type, absolute(url), title)),
+            event => event,
+        })
+        .collect()
+}
+
+/// The root-absolute form of a relative URL, like `/notes/usage.md` for `usage.md` in
+/// `/notes/index.md`
+///
+/// External, absolute and anchor-only URLs are left alone (giving `None`). `..` can't climb above
+/// the root.
+fn absolute_url(url: &str, url_path: &str) -> Option<String> {
+    if url.is_empty() || url.starts_with(['/', '#', '?']) || is_external(url) {
+        return None;
+    }
+    let end = url.find(['?', '#']).unwrap_or(url.len());
+    let (path, rest) = url.split_at(end);
+    let dir = url_path.rsplit_once('/').map(|(dir, _)| dir).unwrap_or_default();
+    let dir = url_escape::encode_path(dir);
+    let mut segments: Vec<&str> = dir.split('/').filter(|s| !s.is_empty()).collect();
+    let mut parts = path.split('/').peekable();
+    while let Some(part) = parts.next() {
+        match part {
+            "." | "" if parts.peek().is_some() => continue,
+            ".." => { segments.pop(); },
+            // A trailing `/` or `.` leaves a directory
+            "." | "" => segments.push(""),
+            part => segments.push(part),
+        }
+    }
+    Some(format!("/{}{rest}", segments.join("/")))
+}
+
+// }}}
+
+// Headings {{{
+
+/// Collects the headings of a note as its events go by
+#[derive(Default)]
+pub(crate) struct HeadingReader {
+    headings: Vec<Heading>,
+    /// The heading currently being parsed
+    current: Option<Heading>,
+}
+
+impl HeadingReader {
+    /// Takes in an event, putting the math of `math` back into heading text
+    pub(crate) fn read(&mut self, event: &Event, math: &Extracted) {
+        match event {
+            Event::Start(Tag::Heading(level, id, _)) => {
+                let id = id.unwrap_or_default().to_string();
+                self.current = Some(Heading { level: *level as u32, text: String::new(), id });
+            },
+            Event::End(Tag::Heading(..)) => {
+                if let Some(h) = self.current.take() {
+                    self.headings.push(h);
+                }
+            },
+            Event::Text(text) | Event::Code(text) => {
+                if let Some(h) = self.current.as_mut() {
+                    h.text.push_str(&math.restore(text));
+                }
+            },
+            _ => (),
+        }
+    }
+
+    pub(crate) fn finish(self) -> Vec<Heading> {
+        self.headings
+    }
+}
+
+/// Text of the first top-level heading, which titles a note without one in its metadata
+pub(crate) fn title(headings: &[Heading]) -> Option<String> {
+    headings.iter().find(|h| h.level == 1).map(|h| h.text.clone())
+}
+
+/// Gives every heading a unique id, keeping the ones that were given in the note
+///
+/// Ids made from the text get a number when they're taken, like `notes-1`, so that they stay the
+/// same as long as the headings before them do. A given id that repeats keeps it the first time,
+/// and is numbered the same way after that.
+fn assign_ids(headings: &mut [Heading]) {
+    let mut taken: HashSet<String> = HashSet::new();
+    let mut repeated = vec![false; headings.len()];
+    for (heading, repeated) in headings.iter().zip(repeated.iter_mut()) {
+        if !heading.id.is_empty() && !taken.insert(heading.id.clone()) {
+            *repeated = true;
+        }
+    }
+    for (heading, repeated) in headings.iter_mut().zip(repeated) {
+        if !heading.id.is_empty() && !repeated {
+            continue;
+        }
+        let slug = if repeated { std::mem::take(&mut heading.id) } else { slug(&heading.text) };
+        let mut id = slug.clone();
+        let mut n = 0;
+        while taken.contains(&id) {
+            n += 1;
+            id = format!("{slug}-{n}");
+        }
+        taken.insert(id.clone());
+        heading.id = id;
+    }
+}
+
+/// Makes an id out of heading text, like GitHub does: `Notes & To-dos` becomes `notes--to-dos`
+pub(crate) fn slug(text: &str) -> String {
+    let slug: String = text.trim().chars()
+        .filter_map(|c| match c {
+            c if c.is_alphanumeric() || c == '-' || c == '_' => Some(c),
+            c if c.is_whitespace() => Some('-'),
+            _ => None,
+        })
+        .flat_map(char::to_lowercase)
+        .collect();
+    if slug.is_empty() { "section".to_string() } else { slug }
+}
+
+/// Puts the ids of `headings` on the heading tags, in order
+fn with_heading_ids<'a>(events: Vec<Event<'a>>, headings: &'a [Heading]) -> Vec<Event<'a>> {
+    let mut ids = headings.iter().map(|h| h.id.as_str());
+    events.into_iter()
+        .map(|event| match event {
+            Event::Start(Tag::Heading(level, id, classes)) => {
+                Event::Start(Tag::Heading(level, ids.next().or(id), classes))
+            },
+            event => event,
+        })
+        .collect()
+}
+
+/// Nests the headings under the closest heading before them with a lower level
+fn toc(headings: &[Heading]) -> Vec<TocEntry> {
+    let mut roots: Vec<TocEntry> = Vec::new();
+    for heading in headings {
+        let entry = TocEntry {
+            level: heading.level,
+            text: heading.text.clone(),
+            id: heading.id.clone(),
+            children: Vec::new(),
+        };
+        let mut siblings = &mut roots;
+        while siblings.last().is_some_and(|last| last.level < entry.level) {
+            siblings = &mut siblings.last_mut().expect("checked by the loop").children;
+        }
+        siblings.push(entry);
+    }
+    roots
+}
+
+/// Replaces paragraphs of just `[TOC]` with the table of contents
+fn with_toc<'a>(events: Vec<Event<'a>>, toc: &[TocEntry]) -> Vec<Event<'a>> {
+    let mut out = Vec::with_capacity(events.len());
+    let mut events = events.into_iter();
+    while let Some(event) = events.next() {
+        if event != Event::Start(Tag::Paragraph) {
+            out.push(event);
+            continue;
+        }
+        // NOTE: the brackets can come as separate text events
+        let mut paragraph = vec![event];
+        for event in events.by_ref() {
+            let end = event == Event::End(Tag::Paragraph);
+            paragraph.push(event);
+            if end {
+                break;
+            }
+        }
+        let text: Option<String> = paragraph.get(1..paragraph.len() - 1).unwrap_or_default().iter()
+            .map(|e| match e {
+                Event::Text(text) => Some(text.as_ref()),
+                _ => None,
+            })
+            .collect();
+        match text {
+            Some(text) if text.trim() == TOC_MARKER => out.push(Event::Html(CowStr::from(toc_html(toc)))),
+            _ => out.extend(paragraph),
+        }
+    }
+    out
+}
+
+fn toc_html(toc: &[TocEntry]) -> String {
+    fn list(entries: &[TocEntry], out: &mut String) {
+        out.push_str("<ul>\n");
+        for entry in entries {
+            out.push_str("<li><a href=\"#");
+            escape_href(&mut *out, &entry.id).expect("writing to a string can't fail");
+            out.push_str("\">");
+            escape_html(&mut *out, &entry.text).expect("writing to a string can't fail");
+            out.push_str("</a>");
+            if !entry.children.is_empty() {
+                list(&entry.children, out);
+            }
+            out.push_str("</li>\n");
+        }
+        out.push_str("</ul>\n");
+    }
+    let mut out = String::from("<nav class=\"toc\">\n");
+    list(toc, &mut out);
+    out.push_str("</nav>\n");
+    out
+}
+
+// }}}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(source: &str) -> Note {
+        Renderer::default().render(source.to_string(), "/notes/index.md", &WikiLinks::default())
+    }
+
+    #[test]
+    fn gives_headings_unique_ids() {
+        let note = render("# Notes\n## To-do & done\n## Notes\n## Notes\n### Mine {#notes-1}\n");
+        let ids: Vec<&str> = note.headings.iter().map(|h| h.id.as_str()).collect();
+        assert_eq!(ids, vec!["notes", "to-do--done", "notes-2", "notes-3", "notes-1"]);
+        assert!(note.html.starts_with("<h1 id=\"notes\">Notes</h1>\n<h2 id=\"to-do--done\">"), "{}", note.html);
+        let note = render("# A {#x}\n# B {#x}\n# X\n# C {#x-1}\n");
+        let ids: Vec<&str> = note.headings.iter().map(|h| h.id.as_str()).collect();
+        assert_eq!(ids, vec!["x", "x-2", "x-3", "x-1"]);
+        assert_eq!(slug("  Café_2 "), "café_2");
+        assert_eq!(slug("???"), "section");
+    }
+
+    #[test]
+    fn nests_headings() {
+        let note = render("## Before\n# Title\n### Deep\n## Section\n");
+        let outline: Vec<(&str, Vec<&str>)> = note.toc.iter()
+            .map(|e| (e.id.as_str(), e.children.iter().map(|c| c.id.as_str()).collect()))
+            .collect();
+        assert_eq!(outline, vec![("before", vec![]), ("title", vec!["deep", "section"])]);
+    }
+
+    #[test]
+    fn expands_toc_marker() {
+        let note = render("# A & B\n\n[TOC]\n\n## B\n\nNot [TOC] here\n");
+        assert!(note.html.contains(concat!(
+            "<nav class=\"toc\">\n<ul>\n<li><a href=\"#a--b\">A &amp; B</a><ul>\n",
+            "<li><a href=\"#b\">B</a></li>\n</ul>\n</li>\n</ul>\n</nav>\n",
+        )), "{}", note.html);
+        assert!(note.html.contains("<p>Not [TOC] here</p>"));
+    }
+
+    #[test]
+    fn parses_extensions() {
+        let default = Extensions::default();
+        assert_eq!(default.to_string(), DEFAULT_EXTENSIONS);
+        assert_eq!(default.options(), Options::from_bits_truncate(0b1011110));
+        assert_eq!("none".parse::<Extensions>().unwrap().to_string(), "none");
+        assert_eq!(" Tables,footnotes ".parse::<Extensions>().unwrap().to_string(), "tables, footnotes");
+        assert!("tables, emoji".parse::<Extensions>().is_err());
+    }
+
+    #[test]
+    fn notes_change_extensions() {
+        let source = "+++\nextensions = [\"smart-punctuation\", \"-strikethrough\"]\n+++\n\"Hi\" ~~x~~\n";
+        assert_eq!(render(source).html, "<p>“Hi” ~~x~~</p>\n");
+        let source = "---\nextensions: -tables, nonsense\n---\n~~x~~\n";
+        assert_eq!(render(source).html, "<p><del>x</del></p>\n");
+    }
+
+    #[test]
+    fn makes_urls_absolute() {
+        let note = render(concat!(
+            "[a](usage.md) [b](../img/x%20y.png?v=1#top) [c](./sub/) ![d](pic.png) [e](../../../up.md)\n",
+            "[f](/root.md) [g](#here) [h](https://example.com) <me@example.com>\n",
+        ));
+        for href in [
+            "href=\"/notes/usage.md\"", "href=\"/img/x%20y.png?v=1#top\"", "href=\"/notes/sub/\"",
+            "src=\"/notes/pic.png\"", "href=\"/up.md\"", "href=\"/root.md\"", "href=\"#here\"",
+            "href=\"https://example.com\"", "href=\"mailto:me@example.com\"",
+        ] {
+            assert!(note.html.contains(href), "{href} not in {}", note.html);
+        }
+        assert_eq!(note.links[0].url, "usage.md");
+    }
+}
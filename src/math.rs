@@ -0,0 +1,611 @@
+//! LaTeX math in notes
+//!
+//! Markdown and LaTeX disagree about `_`, `*` and `\`, so math is found before the markdown is
+//! parsed, and is swapped for placeholders that the parser leaves alone. After parsing, each
+//! placeholder becomes the math exactly as it was written (delimiters included), wrapped in a
+//! `<span class="math math-inline">` or `<span class="math math-display">` for the client to
+//! typeset.
+//!
+//! The delimiters are configurable, and should match the ones given to MathJax in `base.html`.
+//!
+//! With [MathMode::Mathml], the math is instead converted to MathML by KaTeX on the server, so the
+//! client has nothing left to do. Math that KaTeX can't parse is shown as it was written, marked
+//! as an error.
+
+use std::{borrow::Cow, collections::HashMap, fmt, fs, path::Path, str::FromStr};
+
+use pulldown_cmark::{Event, Tag, CowStr, escape::escape_html};
+use tracing::{debug, warn};
+
+/// Inline delimiters, as configured for MathJax in `base.html`
+pub const DEFAULT_INLINE: &str = "$$, \\( \\)";
+/// Display delimiters, as configured for MathJax in `base.html`
+pub const DEFAULT_DISPLAY: &str = "\\[ \\]";
+
+/// Marks the start and end of a placeholder, from Unicode's private use area
+const OPEN_MARK: char = '\u{E000}';
+const CLOSE_MARK: char = '\u{E001}';
+
+/// The opening and closing delimiters of math, e.g. `\(` and `\)`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Delimiter {
+    pub open: String,
+    pub close: String,
+}
+
+impl FromStr for Delimiter {
+    type Err = String;
+
+    /// Parses the delimiters separated by a space, like `\( \)`, or a single one for both, like `$`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        match parts[..] {
+            [both] => Ok(Delimiter { open: both.to_string(), close: both.to_string() }),
+            [open, close] => Ok(Delimiter { open: open.to_string(), close: close.to_string() }),
+            _ => Err(format!("invalid math delimiters '{s}' (expected e.g. '$' or '\\( \\)')")),
+        }
+    }
+}
+
+impl fmt::Display for Delimiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.open == self.close {
+            write!(f, "{}", self.open)
+        } else {
+            write!(f, "{} {}", self.open, self.close)
+        }
+    }
+}
+
+/// A list of delimiters, written comma-separated; `none` is the empty list
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Delimiters(pub Vec<Delimiter>);
+
+impl FromStr for Delimiters {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim().is_empty() || s.trim().eq_ignore_ascii_case("none") {
+            return Ok(Delimiters(Vec::new()));
+        }
+        s.split(',').map(str::parse).collect::<Result<_, _>>().map(Delimiters)
+    }
+}
+
+impl fmt::Display for Delimiters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "none");
+        }
+        let list: Vec<String> = self.0.iter().map(Delimiter::to_string).collect();
+        write!(f, "{}", list.join(", "))
+    }
+}
+
+/// Where math is typeset
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum MathMode {
+    /// In the browser, by MathJax
+    #[default]
+    Client,
+    /// On the server, as MathML
+    Mathml,
+}
+
+impl FromStr for MathMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "client" => Ok(MathMode::Client),
+            "mathml" => Ok(MathMode::Mathml),
+            _ => Err(format!("unknown math mode '{s}' (expected client or mathml)")),
+        }
+    }
+}
+
+impl fmt::Display for MathMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MathMode::Client => write!(f, "client"),
+            MathMode::Mathml => write!(f, "mathml"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Inline,
+    Display,
+}
+
+/// Finds math in markdown source
+#[derive(Debug)]
+pub struct Math {
+    /// Longest opening delimiter first, so that `$$` is tried before `$`
+    delimiters: Vec<(Delimiter, Mode)>,
+    /// KaTeX options for inline and display math, when rendering MathML
+    mathml: Option<(katex::Opts, katex::Opts)>,
+}
+
+impl Math {
+    /// The math finder for the delimiters, unless there are none
+    pub fn new(inline: &Delimiters, display: &Delimiters) -> Option<Self> {
+        let mut delimiters: Vec<(Delimiter, Mode)> = display.0.iter().map(|d| (d.clone(), Mode::Display))
+            .chain(inline.0.iter().map(|d| (d.clone(), Mode::Inline)))
+            .filter(|(d, _)| !d.open.is_empty() && !d.close.is_empty())
+            .collect();
+        if delimiters.is_empty() {
+            return None;
+        }
+        // NOTE: the sort is stable, so display delimiters win over inline ones of the
+        // same length
+        delimiters.sort_by_key(|(d, _)| std::cmp::Reverse(d.open.len()));
+        Some(Self { delimiters, mathml: None })
+    }
+
+    /// Renders the math as MathML, with the macros in a TOML file
+    ///
+    /// The file is a table of macros and their definitions, like `"\\R" = "\\mathbb{R}"`. If it
+    /// can't be read, the math is rendered without macros.
+    pub fn with_mathml(mut self, macros: Option<&Path>) -> Self {
+        let macros = match macros.map(load_macros) {
+            Some(Ok(macros)) => macros,
+            Some(Err(e)) => {
+                warn!("{e}; rendering math without macros");
+                HashMap::new()
+            },
+            None => HashMap::new(),
+        };
+        let opts = |display: bool| {
+            let mut opts = katex::Opts::builder()
+                .display_mode(display)
+                .output_type(katex::OutputType::Mathml)
+                .throw_on_error(true)
+                .build()
+                .expect("all of the options are set");
+            for (name, definition) in &macros {
+                opts.add_macro(name.clone(), definition.clone());
+            }
+            opts
+        };
+        self.mathml = Some((opts(false), opts(true)));
+        self
+    }
+
+    /// Replaces all the math in `source` with placeholders
+    ///
+    /// Math is not looked for in code blocks and code spans, and doesn't continue past a blank
+    /// line.
+    pub fn extract<'a>(&self, source: &'a str) -> Extracted<'a> {
+        let mut text = String::new();
+        let mut spans = Vec::new();
+        // Where the source hasn't been copied to `text` from
+        let mut copied = 0;
+        // The character and length of the code fence we're in
+        let mut fence: Option<(char, usize)> = None;
+        let mut i = 0;
+        while i < source.len() {
+            let rest = &source[i..];
+            if i == 0 || source.as_bytes()[i - 1] == b'\n' {
+                let line = rest.split_inclusive('\n').next().unwrap_or(rest);
+                match (fence, code_fence(line)) {
+                    (None, Some((c, len, _))) => fence = Some((c, len)),
+                    (Some((open, open_len)), Some((c, len, info)))
+                        if c == open && len >= open_len && info.is_empty() => fence = None,
+                    _ => (),
+                }
+                if fence.is_some() || code_fence(line).is_some() {
+                    i += line.len();
+                    continue;
+                }
+            }
+            if rest.starts_with('`') {
+                i = skip_code_span(source, i);
+                continue;
+            }
+            if let Some((end, delim, mode)) = self.find_math(source, i) {
+                text.push_str(&source[copied..i]);
+                text.push(OPEN_MARK);
+                text.push_str(&spans.len().to_string());
+                text.push(CLOSE_MARK);
+                spans.push(self.found(&source[i..end], delim, mode));
+                copied = end;
+                i = end;
+                continue;
+            }
+            let mut chars = rest.chars();
+            match (chars.next(), chars.next()) {
+                // Escaped characters never start math
+                (Some('\\'), Some(next)) if next.is_ascii_punctuation() => i += 2,
+                (Some(c), _) => i += c.len_utf8(),
+                (None, _) => break,
+            }
+        }
+        if spans.is_empty() {
+            return Extracted::plain(source);
+        }
+        text.push_str(&source[copied..]);
+        Extracted { text: Cow::Owned(text), spans }
+    }
+
+    /// Some math, and the html to replace it with
+    fn found(&self, source: &str, delim: &Delimiter, mode: Mode) -> Found {
+        let html = match &self.mathml {
+            None => span(mode, source),
+            Some((inline, display)) => {
+                let opts = if mode == Mode::Display { display } else { inline };
+                match katex::render_with_opts(&source[delim.open.len()..source.len() - delim.close.len()], opts) {
+                    Ok(mathml) => mathml_span(mode, &mathml),
+                    Err(e) => error_span(source, &e),
+                }
+            },
+        };
+        Found { source: source.to_string(), html }
+    }
+
+    /// The math starting at `i`, as where it ends, and its delimiters and mode
+    fn find_math(&self, source: &str, i: usize) -> Option<(usize, &Delimiter, Mode)> {
+        let rest = &source[i..];
+        for (delim, mode) in &self.delimiters {
+            if !rest.starts_with(&delim.open) {
+                continue;
+            }
+            let content = i + delim.open.len();
+            // NOTE: delimiters like `$` are also used as plain text ("$5 and $10"), so they
+            // only count when the math is snug against them, as in pandoc
+            let strict = *mode == Mode::Inline && !delim.open.starts_with('\\');
+            if strict && source[content..].starts_with(char::is_whitespace) {
+                continue;
+            }
+            let mut from = content;
+            while let Some(close) = find_close(source, from, &delim.close) {
+                let end = close + delim.close.len();
+                let math = &source[content..close];
+                let snug = !math.ends_with(char::is_whitespace)
+                    && !source[end..].starts_with(|c: char| c.is_ascii_digit());
+                if !math.trim().is_empty() && (!strict || snug) {
+                    return Some((end, delim, *mode));
+                }
+                from = end;
+            }
+        }
+        None
+    }
+}
+
+/// Markdown source with its math taken out
+#[derive(Debug)]
+pub struct Extracted<'a> {
+    /// The source, with placeholders in place of the math
+    pub text: Cow<'a, str>,
+    spans: Vec<Found>,
+}
+
+/// Some math, as it was written and as html
+#[derive(Debug)]
+struct Found {
+    source: String,
+    html: String,
+}
+
+impl<'a> Extracted<'a> {
+    /// Source without any math
+    pub fn plain(source: &'a str) -> Self {
+        Self { text: Cow::Borrowed(source), spans: Vec::new() }
+    }
+
+    /// Puts the math back into some text, as it was written
+    pub fn restore<'t>(&self, text: &'t str) -> Cow<'t, str> {
+        if self.spans.is_empty() || !text.contains(OPEN_MARK) {
+            return Cow::Borrowed(text);
+        }
+        let mut out = String::with_capacity(text.len());
+        self.replace(text, |piece| match piece {
+            Piece::Text(t) => out.push_str(t),
+            Piece::Math(math) => out.push_str(&math.source),
+        });
+        Cow::Owned(out)
+    }
+
+    /// Puts spaces in place of the math in some text, as many as it was written with
+    pub fn blank<'t>(&self, text: &'t str) -> Cow<'t, str> {
+        if self.spans.is_empty() || !text.contains(OPEN_MARK) {
+            return Cow::Borrowed(text);
+        }
+        let mut out = String::with_capacity(text.len());
+        self.replace(text, |piece| match piece {
+            Piece::Text(t) => out.push_str(t),
+            Piece::Math(math) => out.push_str(&" ".repeat(math.source.len())),
+        });
+        Cow::Owned(out)
+    }
+
+    /// Replaces the placeholders in parsed markdown with the math
+    ///
+    /// In text, the math becomes a span for the client to typeset, or MathML. Anywhere else (like
+    /// code, or a link's URL), the math is put back as it was written.
+    pub fn events<'e>(&self, events: impl Iterator<Item = Event<'e>>) -> Vec<Event<'e>> {
+        if self.spans.is_empty() {
+            return events.collect();
+        }
+        let mut out = Vec::new();
+        let mut in_code = false;
+        for event in events {
+            match event {
+                Event::Start(Tag::CodeBlock(_)) => {
+                    in_code = true;
+                    out.push(event);
+                },
+                Event::End(Tag::CodeBlock(_)) => {
+                    in_code = false;
+                    out.push(event);
+                },
+                Event::Text(text) if !in_code && text.contains(OPEN_MARK) => {
+                    self.replace(&text, |piece| match piece {
+                        Piece::Text(t) => out.push(Event::Text(CowStr::from(t.to_string()))),
+                        Piece::Math(math) => out.push(Event::Html(CowStr::from(math.html.clone()))),
+                    });
+                },
+                Event::Text(text) => out.push(Event::Text(self.restore_cow(text))),
+                Event::Code(text) => out.push(Event::Code(self.restore_cow(text))),
+                Event::Html(text) => out.push(Event::Html(self.restore_cow(text))),
+                Event::Start(Tag::Link(kind, url, title)) => {
+                    out.push(Event::Start(Tag::Link(kind, self.restore_cow(url), self.restore_cow(title))));
+                },
+                Event::Start(Tag::Image(kind, url, title)) => {
+                    out.push(Event::Start(Tag::Image(kind, self.restore_cow(url), self.restore_cow(title))));
+                },
+                event => out.push(event),
+            }
+        }
+        out
+    }
+
+    fn restore_cow<'e>(&self, text: CowStr<'e>) -> CowStr<'e> {
+        match self.restore(&text) {
+            Cow::Borrowed(_) => text,
+            Cow::Owned(restored) => CowStr::from(restored),
+        }
+    }
+
+    /// Splits text at its placeholders
+    fn replace<'t>(&'t self, mut text: &'t str, mut f: impl FnMut(Piece<'t>)) {
+        while let Some(start) = text.find(OPEN_MARK) {
+            let after = &text[start + OPEN_MARK.len_utf8()..];
+            let span = after.find(CLOSE_MARK)
+                .and_then(|end| Some((end, after[..end].parse::<usize>().ok()?)))
+                .and_then(|(end, n)| Some((end, self.spans.get(n)?)));
+            match span {
+                Some((end, math)) => {
+                    f(Piece::Text(&text[..start]));
+                    f(Piece::Math(math));
+                    text = &after[end + CLOSE_MARK.len_utf8()..];
+                },
+                // Not one of ours, so it's left as it is
+                None => {
+                    f(Piece::Text(&text[..start + OPEN_MARK.len_utf8()]));
+                    text = after;
+                },
+            }
+        }
+        f(Piece::Text(text));
+    }
+}
+
+enum Piece<'t> {
+    Text(&'t str),
+    Math(&'t Found),
+}
+
+/// The html for some math
+fn span(mode: Mode, source: &str) -> String {
+    let class = match mode {
+        Mode::Inline => "math math-inline",
+        Mode::Display => "math math-display",
+    };
+    let mut html = format!("<span class=\"{class}\">");
+    escape_html(&mut html, source).expect("writing to a string can't fail");
+    html.push_str("</span>");
+    html
+}
+
+/// The html for math rendered by KaTeX
+///
+/// These spans don't have the `math` class, since the client has nothing to do with them.
+fn mathml_span(mode: Mode, mathml: &str) -> String {
+    let class = match mode {
+        Mode::Inline => "mathml mathml-inline",
+        Mode::Display => "mathml mathml-display",
+    };
+    format!("<span class=\"{class}\">{mathml}</span>")
+}
+
+/// The html for math that KaTeX couldn't render, with the reason in its title
+fn error_span(source: &str, error: &katex::Error) -> String {
+    let message = match error {
+        // NOTE: the JS engine gives the error as a debug-formatted value, like
+        // `String("ParseError: ...")`, whose quoting is close enough to JSON
+        katex::Error::JsExecError(message) => message.strip_prefix("String(")
+            .and_then(|m| m.strip_suffix(')'))
+            .and_then(|m| serde_json::from_str(m).ok())
+            .unwrap_or_else(|| message.to_string()),
+        e => e.to_string(),
+    };
+    debug!("could not render math {source:?}: {message}");
+    let mut html = String::from("<span class=\"mathml-error\" title=\"");
+    escape_html(&mut html, &message).expect("writing to a string can't fail");
+    html.push_str("\">");
+    escape_html(&mut html, source).expect("writing to a string can't fail");
+    html.push_str("</span>");
+    html
+}
+
+/// Reads a TOML table of macros, adding the leading `\` to names without it
+fn load_macros(path: &Path) -> Result<HashMap<String, String>, String> {
+    let error = |e: &dyn fmt::Display| format!("could not load math macros from {}: {e}", path.display());
+    let contents = fs::read_to_string(path).map_err(|e| error(&e))?;
+    let table: HashMap<String, String> = toml::from_str(&contents).map_err(|e| error(&e))?;
+    Ok(table.into_iter()
+        .map(|(name, definition)| match name.starts_with('\\') {
+            true => (name, definition),
+            false => (format!("\\{name}"), definition),
+        })
+        .collect())
+}
+
+/// The fence character, fence length and info string, if the line opens or closes a code block
+fn code_fence(line: &str) -> Option<(char, usize, &str)> {
+    let indent = line.len() - line.trim_start_matches(' ').len();
+    if indent > 3 {
+        return None;
+    }
+    let line = line.trim();
+    let c = line.chars().next().filter(|c| matches!(c, '`' | '~'))?;
+    let len = line.len() - line.trim_start_matches(c).len();
+    (len >= 3).then(|| (c, len, line[len..].trim()))
+}
+
+/// Index just past the code span starting at `i`, or past its backticks if it isn't closed
+fn skip_code_span(source: &str, i: usize) -> usize {
+    let run = source[i..].len() - source[i..].trim_start_matches('`').len();
+    let mut j = i + run;
+    while j < source.len() {
+        let rest = &source[j..];
+        if rest.starts_with('`') {
+            let len = rest.len() - rest.trim_start_matches('`').len();
+            if len == run {
+                return j + len;
+            }
+            j += len;
+        } else if is_paragraph_break(source, j) {
+            break;
+        } else {
+            j += rest.chars().next().map_or(1, char::len_utf8);
+        }
+    }
+    i + run
+}
+
+/// Where `close` is, from `from`, skipping escaped characters and stopping at a blank line
+fn find_close(source: &str, from: usize, close: &str) -> Option<usize> {
+    let mut j = from;
+    while j < source.len() {
+        let rest = &source[j..];
+        if rest.starts_with(close) {
+            return Some(j);
+        }
+        if is_paragraph_break(source, j) {
+            return None;
+        }
+        let mut chars = rest.chars();
+        j += match (chars.next(), chars.next()) {
+            (Some('\\'), Some(next)) => 1 + next.len_utf8(),
+            (Some(c), _) => c.len_utf8(),
+            (None, _) => break,
+        };
+    }
+    None
+}
+
+/// Whether the line break at `i` is followed by a blank line
+fn is_paragraph_break(source: &str, i: usize) -> bool {
+    source[i..].strip_prefix('\n')
+        .map(|rest| rest.split('\n').next().unwrap_or("").trim().is_empty())
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pulldown_cmark::{Parser, html};
+
+    fn math(inline: &str, display: &str) -> Math {
+        Math::new(&inline.parse().unwrap(), &display.parse().unwrap()).unwrap()
+    }
+
+    fn render(source: &str, math: &Math) -> String {
+        let extracted = math.extract(source);
+        let mut out = String::new();
+        html::push_html(&mut out, extracted.events(Parser::new(&extracted.text)).into_iter());
+        out
+    }
+
+    #[test]
+    fn parses_delimiters() {
+        let delims: Delimiters = "$, \\( \\)".parse().unwrap();
+        assert_eq!(delims.0[0], Delimiter { open: "$".into(), close: "$".into() });
+        assert_eq!(delims.0[1], Delimiter { open: "\\(".into(), close: "\\)".into() });
+        assert_eq!(delims.to_string(), "$, \\( \\)");
+        assert_eq!("none".parse::<Delimiters>().unwrap(), Delimiters::default());
+        assert!("a b c".parse::<Delimiter>().is_err());
+        assert!(Math::new(&Delimiters::default(), &Delimiters::default()).is_none());
+    }
+
+    #[test]
+    fn passes_math_through() {
+        let math = math("$", "$$, \\[ \\]");
+        assert_eq!(render("Let $a_1 * b_2$ be\n", &math),
+                   "<p>Let <span class=\"math math-inline\">$a_1 * b_2$</span> be</p>\n");
+        assert_eq!(render("\\[\n\\alpha_i < \\beta_i \\\\\n\\]\n", &math),
+                   "<p><span class=\"math math-display\">\\[\n\\alpha_i &lt; \\beta_i \\\\\n\\]</span></p>\n");
+        assert_eq!(render("*$$x^*$$*", &math),
+                   "<p><em><span class=\"math math-display\">$$x^*$$</span></em></p>\n");
+    }
+
+    #[test]
+    fn leaves_dollars_in_text() {
+        let math = math("$", "$$");
+        assert_eq!(render("From $5 to $10\n", &math), "<p>From $5 to $10</p>\n");
+        assert_eq!(render("Escaped \\$x$\n", &math), "<p>Escaped $x$</p>\n");
+        assert_eq!(render("$a\n\nb$\n", &math), "<p>$a</p>\n<p>b$</p>\n");
+    }
+
+    #[test]
+    fn skips_code() {
+        let math = math("$", "$$");
+        assert_eq!(render("`$x_1$` and $y_1$\n", &math),
+                   "<p><code>$x_1$</code> and <span class=\"math math-inline\">$y_1$</span></p>\n");
+        assert_eq!(render("```\n$x_1$\n```\n", &math), "<pre><code>$x_1$\n</code></pre>\n");
+        // Not found by the scanner, but put back as it was
+        assert_eq!(render("    $x_1$\n", &math), "<pre><code>$x_1$\n</code></pre>\n");
+    }
+
+    #[test]
+    fn renders_mathml() {
+        let math = math("$", "$$").with_mathml(None);
+        let out = render("Let $x_1$ be\n", &math);
+        assert!(out.starts_with("<p>Let <span class=\"mathml mathml-inline\"><span class=\"katex\"><math"), "{out}");
+        assert!(out.contains("<msub><mi>x</mi><mn>1</mn></msub>"), "{out}");
+        let out = render("$$\\frac{a}{b}$$\n", &math);
+        assert!(out.contains("display=\"block\""), "{out}");
+    }
+
+    #[test]
+    fn shows_invalid_math() {
+        let math = math("$", "$$").with_mathml(None);
+        let out = render("Oops $\\frac{a$\n", &math);
+        assert!(out.starts_with("<p>Oops <span class=\"mathml-error\" title=\"ParseError: KaTeX parse error"), "{out}");
+        assert!(out.ends_with("\">$\\frac{a$</span></p>\n"), "{out}");
+    }
+
+    #[test]
+    fn loads_macros() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("macros.toml");
+        fs::write(&path, "R = '\\mathbb{R}'\n'\\eps' = '\\varepsilon'\n").unwrap();
+        let macros = load_macros(&path).unwrap();
+        assert_eq!(macros["\\R"], "\\mathbb{R}");
+        assert_eq!(macros["\\eps"], "\\varepsilon");
+        let math = math("$", "$$").with_mathml(Some(&path));
+        assert!(render("$x \\in \\R$", &math).contains("<mi mathvariant=\"double-struck\">R</mi>"));
+    }
+
+    #[test]
+    fn restores_headings() {
+        let math = math("$", "$$");
+        let extracted = math.extract("# Energy $E_k$");
+        assert_ne!(extracted.text, "# Energy $E_k$");
+        assert_eq!(extracted.restore(&extracted.text), "# Energy $E_k$");
+    }
+}
@@ -0,0 +1,249 @@
+//! Metadata at the top of notes
+//!
+//! Notes can start with a block of TOML, either as `+++` frontmatter or as a fenced ```` ```toml ````
+//! code block. The fenced block may also come just after the note's `# Title`, which is how the
+//! notes in `sample/notes` are written. Notes from other tools often have `---` YAML frontmatter
+//! instead, which is read into the same [Metadata]. The block is taken out of the markdown before
+//! rendering, and its values are given to templates as `meta`.
+
+use std::{collections::BTreeMap, ops::Range};
+
+use serde::Serialize;
+use serde_json::Value;
+use tracing::warn;
+
+/// What a note says about itself
+///
+/// `title`, `date` and `tags` are common to most notes; any other keys are kept in `extra`, and
+/// are serialized alongside them (so a template can use `meta.author` as well as `meta.title`).
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Metadata {
+    pub title: Option<String>,
+    /// The date as it was written, e.g. `2023-11-06`
+    pub date: Option<String>,
+    pub tags: Vec<String>,
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
+}
+
+impl Metadata {
+    /// Picks the known keys out of a parsed block, whichever format it was in
+    fn from_map(map: serde_json::Map<String, Value>) -> Self {
+        let mut meta = Metadata::default();
+        for (key, value) in map {
+            // NOTE: the known keys never go in `extra`, even with the wrong type, since
+            // they would be serialized twice
+            match (key.as_str(), value) {
+                ("title", value) => meta.title = scalar(value),
+                ("date", value) => meta.date = scalar(value),
+                ("tags", Value::Array(tags)) => meta.tags = tags.into_iter().filter_map(scalar).collect(),
+                ("tags", value) => meta.tags = scalar(value).into_iter().collect(),
+                (_, value) => { meta.extra.insert(key, value); },
+            }
+        }
+        meta
+    }
+}
+
+/// A string, number or boolean as a string
+fn scalar(value: Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+/// Formats that a metadata block can be written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Toml,
+    Yaml,
+}
+
+impl Format {
+    /// Parses the contents of a block, which must be a table (or mapping) at the top
+    fn parse(&self, contents: &str) -> Result<Metadata, String> {
+        let value = match self {
+            Format::Toml => toml::from_str::<toml::Table>(contents)
+                .map(|t| toml_to_json(toml::Value::Table(t)))
+                .map_err(|e| e.to_string())?,
+            // NOTE: YAML dates are plain strings, so they need no special handling
+            Format::Yaml => serde_norway::from_str::<Value>(contents).map_err(|e| e.to_string())?,
+        };
+        match value {
+            Value::Object(map) => Ok(Metadata::from_map(map)),
+            // An empty YAML block
+            Value::Null => Ok(Metadata::default()),
+            _ => Err("metadata is not a table".to_string()),
+        }
+    }
+}
+
+/// Converts TOML to JSON values, which tera can use
+///
+/// Dates become strings, rather than the tables that serde would make of them.
+fn toml_to_json(value: toml::Value) -> Value {
+    match value {
+        toml::Value::String(s) => Value::String(s),
+        toml::Value::Integer(i) => Value::from(i),
+        toml::Value::Float(f) => Value::from(f),
+        toml::Value::Boolean(b) => Value::Bool(b),
+        toml::Value::Datetime(d) => Value::String(d.to_string()),
+        toml::Value::Array(a) => Value::Array(a.into_iter().map(toml_to_json).collect()),
+        toml::Value::Table(t) => {
+            Value::Object(t.into_iter().map(|(k, v)| (k, toml_to_json(v))).collect())
+        },
+    }
+}
+
+/// A block of metadata found in a note
+#[derive(Debug, PartialEq)]
+pub struct Block {
+    pub meta: Metadata,
+    pub format: Format,
+    /// The bytes of the source that the block takes up, including its fences
+    pub range: Range<usize>,
+}
+
+/// Finds and parses the metadata block at the top of a note
+///
+/// Blocks that can't be parsed are left alone, and are rendered as they are. For YAML, that is
+/// deliberate: a note may well start with a horizontal rule and a setext heading.
+pub fn extract(source: &str) -> Option<Block> {
+    let (format, range, body) = find_block(source)?;
+    match format.parse(&source[body]) {
+        Ok(meta) => Some(Block { meta, format, range }),
+        Err(e) => {
+            warn!("ignoring invalid {format:?} metadata: {e}");
+            None
+        },
+    }
+}
+
+/// Lines of the source, with their byte ranges (including the line ending)
+fn lines(source: &str) -> impl Iterator<Item = (&str, Range<usize>)> {
+    source.split_inclusive('\n').scan(0, |start, line| {
+        let range = *start..*start + line.len();
+        *start = range.end;
+        Some((line.trim_end_matches(['\n', '\r']), range))
+    })
+}
+
+/// Locates the block, giving its format, the range of the whole block and of its contents
+fn find_block(source: &str) -> Option<(Format, Range<usize>, Range<usize>)> {
+    let mut lines = lines(source).peekable();
+    // NOTE: frontmatter has to be the very first line, but a fenced block can follow the
+    // title
+    let (first, _) = lines.peek()?.clone();
+    if first.trim_end() == "+++" {
+        let (_, open) = lines.next()?;
+        return find_close(Format::Toml, lines, open, |line| line.trim_end() == "+++");
+    }
+    if first.trim_end() == "---" {
+        let (_, open) = lines.next()?;
+        return find_close(Format::Yaml, lines, open, |line| matches!(line.trim_end(), "---" | "..."));
+    }
+    let mut seen_title = false;
+    while let Some((line, range)) = lines.next() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+        if !seen_title && line.starts_with("# ") {
+            seen_title = true;
+            continue;
+        }
+        let fence = trimmed.len() - trimmed.trim_start_matches('`').len();
+        if fence >= 3 && trimmed[fence..].trim().eq_ignore_ascii_case("toml") {
+            return find_close(Format::Toml, lines, range, |line| {
+                let line = line.trim();
+                line.len() >= fence && line.chars().all(|c| c == '`')
+            });
+        }
+        return None;
+    }
+    None
+}
+
+fn find_close<'a>(
+    format: Format,
+    lines: impl Iterator<Item = (&'a str, Range<usize>)>,
+    open: Range<usize>,
+    is_close: impl Fn(&str) -> bool,
+) -> Option<(Format, Range<usize>, Range<usize>)> {
+    for (line, range) in lines {
+        if is_close(line) {
+            return Some((format, open.start..range.end, open.end..range.start));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_fenced_block_after_title() {
+        let source = "# Usage\n\n```toml\ndate = 2023-11-06\ntags = [\"usage\", \"md-server\"]\n```\n\nText\n";
+        let block = extract(source).unwrap();
+        assert_eq!(block.meta.date.as_deref(), Some("2023-11-06"));
+        assert_eq!(block.meta.tags, vec!["usage", "md-server"]);
+        assert_eq!(&source[..block.range.start], "# Usage\n\n");
+        assert_eq!(&source[block.range.end..], "\nText\n");
+    }
+
+    #[test]
+    fn parses_frontmatter() {
+        let source = "+++\ntitle = \"Notes\"\nauthor = { name = \"jladan\" }\n+++\n# Notes\n";
+        let block = extract(source).unwrap();
+        assert_eq!(block.meta.title.as_deref(), Some("Notes"));
+        assert_eq!(block.meta.extra["author"]["name"], "jladan");
+        assert_eq!(&source[block.range.end..], "# Notes\n");
+    }
+
+    #[test]
+    fn ignores_later_blocks() {
+        assert_eq!(extract("Some text\n\n```toml\na = 1\n```\n"), None);
+        assert_eq!(extract("# Title\n## Section\n```toml\na = 1\n```\n"), None);
+        assert_eq!(extract("```rust\nfn main() {}\n```\n"), None);
+    }
+
+    #[test]
+    fn ignores_invalid_blocks() {
+        assert_eq!(extract("```toml\nthis is not toml\n```\n"), None);
+        assert_eq!(extract("+++\ntitle = \"unclosed\"\n"), None);
+    }
+
+    #[test]
+    fn parses_yaml_frontmatter() {
+        let source = "---\ntitle: Imported\ndate: 2024-01-31\ntags:\n  - a\n  - b\naliases: [imp]\n---\nBody\n";
+        let block = extract(source).unwrap();
+        assert_eq!(block.format, Format::Yaml);
+        assert_eq!(block.meta.title.as_deref(), Some("Imported"));
+        assert_eq!(block.meta.date.as_deref(), Some("2024-01-31"));
+        assert_eq!(block.meta.tags, vec!["a", "b"]);
+        assert_eq!(block.meta.extra["aliases"], serde_json::json!(["imp"]));
+        let numeric = extract("---\ntitle: 2024\ntags: [1, x]\n---\n").unwrap();
+        assert_eq!(numeric.meta.title.as_deref(), Some("2024"));
+        assert_eq!(numeric.meta.tags, vec!["1", "x"]);
+        assert_eq!(&source[block.range.end..], "Body\n");
+    }
+
+    #[test]
+    fn leaves_horizontal_rules() {
+        // A rule, and then a setext heading
+        assert_eq!(extract("---\nSome heading\n---\n"), None);
+        assert_eq!(extract("---\n\nText\n"), None);
+    }
+
+    #[test]
+    fn serializes_extra_keys_flat() {
+        let block = extract("+++\ndraft = true\n+++\n").unwrap();
+        let json = serde_json::to_value(&block.meta).unwrap();
+        assert_eq!(json["draft"], true);
+        assert_eq!(json["tags"], serde_json::json!([]));
+    }
+}
@@ -0,0 +1,177 @@
+//! Byte-range requests
+//!
+//! Parsing of the `Range` header (RFC 9110 §14), used by `response::send_file` to answer with
+//! `206 Partial Content`. Only the `bytes` unit is supported; anything else is ignored and the
+//! full file is sent.
+
+/// Upper limit on the number of ranges in one request, to avoid tiny-range abuse
+const MAX_RANGES: usize = 16;
+
+/// An inclusive range of bytes within a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+// NOTE: an inclusive range always holds at least one byte
+#[allow(clippy::len_without_is_empty)]
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /// Value for the `Content-Range` header of this range
+    pub fn content_range(&self, total: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, total)
+    }
+}
+
+/// Outcome of applying a `Range` header to a file of known length
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeRequest {
+    /// No usable range: send the whole file
+    Full,
+    /// One or more satisfiable ranges, sorted and merged
+    Partial(Vec<ByteRange>),
+    /// Syntactically valid, but no range overlaps the file
+    Unsatisfiable,
+}
+
+/// Parses a `Range` header value for a file of `len` bytes
+///
+/// Malformed headers are treated as absent, as the RFC allows, and so is an empty set of ranges
+/// like `bytes=` or `bytes=,`.
+pub fn parse(header: &str, len: u64) -> RangeRequest {
+    let specs = match header.trim().strip_prefix("bytes=") {
+        Some(specs) => specs,
+        None => return RangeRequest::Full,
+    };
+    let specs: Vec<&str> = specs.split(',').map(str::trim).filter(|s| !s.is_empty()).collect();
+    if specs.is_empty() {
+        return RangeRequest::Full;
+    }
+    let mut ranges = Vec::new();
+    for spec in specs {
+        let (first, last) = match spec.split_once('-') {
+            Some(pair) => pair,
+            None => return RangeRequest::Full,
+        };
+        let range = match (first.trim(), last.trim()) {
+            // suffix range: the last `n` bytes
+            ("", suffix) => {
+                let suffix: u64 = match suffix.parse() {
+                    Ok(n) => n,
+                    Err(_) => return RangeRequest::Full,
+                };
+                if suffix == 0 || len == 0 {
+                    None
+                } else {
+                    Some(ByteRange { start: len.saturating_sub(suffix), end: len - 1 })
+                }
+            },
+            (first, last) => {
+                let start: u64 = match first.parse() {
+                    Ok(n) => n,
+                    Err(_) => return RangeRequest::Full,
+                };
+                let end: Option<u64> = if last.is_empty() {
+                    None
+                } else {
+                    match last.parse() {
+                        Ok(n) => Some(n),
+                        Err(_) => return RangeRequest::Full,
+                    }
+                };
+                if end.map(|e| e < start).unwrap_or(false) {
+                    return RangeRequest::Full;
+                }
+                if start >= len {
+                    None
+                } else {
+                    let end = end.map(|e| e.min(len - 1)).unwrap_or(len - 1);
+                    Some(ByteRange { start, end })
+                }
+            },
+        };
+        if let Some(range) = range {
+            ranges.push(range);
+        }
+    }
+    if ranges.is_empty() {
+        return RangeRequest::Unsatisfiable;
+    }
+    let ranges = merge(ranges);
+    if ranges.len() > MAX_RANGES {
+        return RangeRequest::Full;
+    }
+    RangeRequest::Partial(ranges)
+}
+
+/// Sorts ranges and merges the ones that overlap or touch
+fn merge(mut ranges: Vec<ByteRange>) -> Vec<ByteRange> {
+    ranges.sort_by_key(|r| r.start);
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for r in ranges {
+        match merged.last_mut() {
+            Some(prev) if r.start <= prev.end.saturating_add(1) => {
+                prev.end = prev.end.max(r.end);
+            },
+            _ => merged.push(r),
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partial(ranges: &[(u64, u64)]) -> RangeRequest {
+        RangeRequest::Partial(ranges.iter().map(|&(start, end)| ByteRange { start, end }).collect())
+    }
+
+    #[test]
+    fn parses_simple_range() {
+        assert_eq!(parse("bytes=0-499", 1000), partial(&[(0, 499)]));
+    }
+
+    #[test]
+    fn parses_open_range() {
+        assert_eq!(parse("bytes=500-", 1000), partial(&[(500, 999)]));
+    }
+
+    #[test]
+    fn parses_suffix_range() {
+        assert_eq!(parse("bytes=-200", 1000), partial(&[(800, 999)]));
+        assert_eq!(parse("bytes=-2000", 1000), partial(&[(0, 999)]));
+    }
+
+    #[test]
+    fn clamps_end_to_length() {
+        assert_eq!(parse("bytes=900-5000", 1000), partial(&[(900, 999)]));
+    }
+
+    #[test]
+    fn merges_overlapping_ranges() {
+        assert_eq!(parse("bytes=500-600, 0-99, 550-700", 1000), partial(&[(0, 99), (500, 700)]));
+        assert_eq!(parse("bytes=0-9,10-19", 1000), partial(&[(0, 19)]));
+    }
+
+    #[test]
+    fn unsatisfiable_ranges() {
+        assert_eq!(parse("bytes=1000-", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse("bytes=-0", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse("bytes=0-", 0), RangeRequest::Unsatisfiable);
+    }
+
+    #[test]
+    fn malformed_ranges_are_ignored() {
+        assert_eq!(parse("items=0-5", 1000), RangeRequest::Full);
+        assert_eq!(parse("bytes=5", 1000), RangeRequest::Full);
+        assert_eq!(parse("bytes=9-5", 1000), RangeRequest::Full);
+        assert_eq!(parse("bytes=a-b", 1000), RangeRequest::Full);
+        assert_eq!(parse("bytes=", 1000), RangeRequest::Full);
+        assert_eq!(parse("bytes= , ", 1000), RangeRequest::Full);
+    }
+}
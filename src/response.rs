@@ -3,30 +3,149 @@
 //! Mostly just errors, but also sending files
 //!
 //!
-use std::path::Path;
+use std::{
+    path::{Path, PathBuf},
+    io::SeekFrom,
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+};
 
-use tokio::fs::File;
+use bytes::Bytes;
+use serde::Serialize;
+use futures::{future, stream, StreamExt, TryStreamExt};
+use tokio::{fs::File, io::{AsyncReadExt, AsyncSeekExt}};
 use tokio_util::codec::{BytesCodec, FramedRead};
 
-use hyper::{StatusCode, Body, Response, http::HeaderValue};
+use hyper::{StatusCode, Body, Response, HeaderMap, http::HeaderValue, body::HttpBody};
+
+use crate::{
+    range::{self, ByteRange, RangeRequest},
+    conditional::{self, Validators},
+    error::Result,
+};
 
 /// Stream a chunked file, with Content-Type guessed from file extension
-pub async fn send_file(resolved: &Path) -> Response<Body> {
-    let fresult = File::open(resolved).await;
-    if let Ok(file) = fresult {
-        let stream = FramedRead::new(file, BytesCodec::new());
-        let body = Body::wrap_stream(stream);
-        let mut resp = Response::new(body);
-        // Now add content-type
-        let guess = mime_guess::from_path(resolved).first();
-        if let Some(mime) =  guess {
-            resp.headers_mut().append("Content-Type", HeaderValue::from_str(mime.essence_str()).unwrap());
+///
+/// `Range` requests are answered with `206 Partial Content` (as `multipart/byteranges` if several
+/// ranges are asked for), or `416` if none of the ranges can be satisfied. The file's `ETag` and
+/// `Last-Modified` are checked against the request first, so unchanged files get a `304`.
+pub async fn send_file(resolved: &Path, headers: &HeaderMap) -> Result<Response<Body>> {
+    let mut file = File::open(resolved).await?;
+    let metadata = file.metadata().await?;
+    let validators = Validators::from_metadata(&metadata);
+    if validators.not_modified(headers) {
+        return Ok(with_accept_ranges(conditional::not_modified(&validators)));
+    }
+    let len = metadata.len();
+    let mime = mime_guess::from_path(resolved).first();
+    let if_range = headers.get("If-Range")
+        .map(|v| v.to_str().map(|v| validators.if_range_matches(v)).unwrap_or(false))
+        .unwrap_or(true);
+    let ranges = match headers.get("Range").and_then(|v| v.to_str().ok()) {
+        Some(value) if if_range => range::parse(value, len),
+        _ => RangeRequest::Full,
+    };
+    let mut resp = match ranges {
+        RangeRequest::Full => {
+            // NOTE: the length has to come from the metadata, because a streamed body has
+            // no size hint for hyper to use
+            let stream = FramedRead::new(file, BytesCodec::new());
+            let mut resp = Response::new(Body::wrap_stream(stream));
+            resp.headers_mut().insert("Content-Length", HeaderValue::from(len));
+            resp
+        },
+        RangeRequest::Partial(ranges) if ranges.len() == 1 => {
+            let range = ranges[0];
+            file.seek(SeekFrom::Start(range.start)).await?;
+            let stream = FramedRead::new(file.take(range.len()), BytesCodec::new());
+            let mut resp = Response::new(Body::wrap_stream(stream));
+            *resp.status_mut() = StatusCode::PARTIAL_CONTENT;
+            resp.headers_mut().insert("Content-Length", HeaderValue::from(range.len()));
+            resp.headers_mut().insert("Content-Range", HeaderValue::from_str(&range.content_range(len)).unwrap());
+            resp
+        },
+        RangeRequest::Partial(ranges) => {
+            let content_type = mime.as_ref()
+                .map(|m| m.essence_str().to_string())
+                .unwrap_or_else(|| "application/octet-stream".to_string());
+            let mut resp = multipart_ranges(resolved, &content_type, len, ranges);
+            validators.apply(resp.headers_mut());
+            return Ok(with_accept_ranges(resp));
+        },
+        RangeRequest::Unsatisfiable => {
+            let resp = Response::builder()
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header("Content-Range", format!("bytes */{len}"))
+                .body(Body::from("Requested range not satisfiable"))
+                .unwrap();
+            return Ok(with_accept_ranges(resp));
+        },
+    };
+    // Now add content-type
+    if let Some(mime) = mime {
+        resp.headers_mut().append("Content-Type", HeaderValue::from_str(mime.essence_str()).unwrap());
+    }
+    validators.apply(resp.headers_mut());
+    Ok(with_accept_ranges(resp))
+}
+
+fn with_accept_ranges(mut resp: Response<Body>) -> Response<Body> {
+    resp.headers_mut().insert("Accept-Ranges", HeaderValue::from_static("bytes"));
+    resp
+}
+
+/// Builds a `multipart/byteranges` response, reading each range from the file as it is streamed
+fn multipart_ranges(path: &Path, content_type: &str, len: u64, ranges: Vec<ByteRange>) -> Response<Body> {
+    let boundary = format!("{:016x}", RandomState::new().build_hasher().finish());
+    let closing = Bytes::from(format!("\r\n--{boundary}--\r\n"));
+    let parts: Vec<(Bytes, ByteRange)> = ranges.into_iter()
+        .map(|range| {
+            let head = format!("\r\n--{boundary}\r\nContent-Type: {content_type}\r\nContent-Range: {}\r\n\r\n",
+                               range.content_range(len));
+            (Bytes::from(head), range)
+        })
+        .collect();
+    let content_length: u64 = parts.iter()
+        .map(|(head, range)| head.len() as u64 + range.len())
+        .sum::<u64>() + closing.len() as u64;
+
+    let path = path.to_path_buf();
+    let body = stream::iter(parts)
+        .map(move |(head, range)| {
+            stream::once(future::ready(Ok(head)))
+                .chain(stream::once(read_range(path.clone(), range)).try_flatten())
+        })
+        .flatten()
+        .chain(stream::once(future::ready(Ok(closing))));
+    Response::builder()
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header("Content-Type", format!("multipart/byteranges; boundary={boundary}"))
+        .header("Content-Length", content_length)
+        .body(Body::wrap_stream(body))
+        .unwrap()
+}
+
+async fn read_range(path: PathBuf, range: ByteRange)
+    -> std::io::Result<impl futures::Stream<Item = std::io::Result<Bytes>>>
+{
+    let mut file = File::open(&path).await?;
+    file.seek(SeekFrom::Start(range.start)).await?;
+    Ok(FramedRead::new(file.take(range.len()), BytesCodec::new()).map_ok(|b| b.freeze()))
+}
+
+/// Drop the body of a response, for answering HEAD requests
+///
+/// The Content-Length of the body is kept, so the headers are the same as for a GET. Streamed
+/// bodies are dropped without being polled, so the file is never actually read.
+pub fn strip_body(resp: Response<Body>) -> Response<Body> {
+    let (mut parts, body) = resp.into_parts();
+    let has_body = !matches!(parts.status, StatusCode::NOT_MODIFIED | StatusCode::NO_CONTENT);
+    if has_body && !parts.headers.contains_key("Content-Length") {
+        if let Some(len) = body.size_hint().exact() {
+            parts.headers.insert("Content-Length", HeaderValue::from(len));
         }
-        resp
-    } else {
-        // TODO: Probably want to handle the different types of file errors here
-        return not_found();
     }
+    Response::from_parts(parts, Body::empty())
 }
 
 pub fn send_html<T>(contents: T) -> Response<Body>
@@ -37,6 +156,27 @@ pub fn send_html<T>(contents: T) -> Response<Body>
     resp
 }
 
+/// Send text with a given media type, such as `text/markdown`
+pub fn send_text<T>(contents: T, media_type: &str) -> Response<Body>
+    where Body: From<T>
+{
+    let mut resp = Response::new(Body::from(contents));
+    let content_type = format!("{media_type}; charset=utf-8");
+    resp.headers_mut().append("Content-Type", HeaderValue::from_str(&content_type).unwrap());
+    resp
+}
+
+pub fn send_json<T: Serialize + ?Sized>(value: &T) -> Response<Body> {
+    match serde_json::to_string(value) {
+        Ok(contents) => {
+            let mut resp = Response::new(Body::from(contents));
+            resp.headers_mut().append("Content-Type", HeaderValue::from_static("application/json"));
+            resp
+        },
+        Err(e) => server_error(&e.to_string()),
+    }
+}
+
 pub fn not_found() -> Response<Body> {
     Response::builder()
         .status(StatusCode::NOT_FOUND)
@@ -58,10 +198,13 @@ pub fn server_error(msg: &str) -> Response<Body> {
        .unwrap()
 }
 
-pub fn not_acceptable() -> Response<Body> {
+/// Lists the media types that could have been provided instead
+pub fn not_acceptable(available: &[&str]) -> Response<Body> {
     Response::builder()
        .status(StatusCode::NOT_ACCEPTABLE)
-       .body(Body::from("Requested format can not be provided"))
+       .header("Content-Type", "text/plain; charset=utf-8")
+       .body(Body::from(format!("Requested format can not be provided\nAvailable: {}\n",
+                                available.join(", "))))
        .unwrap()
 }
 
@@ -0,0 +1,347 @@
+//! Serving over HTTPS
+//!
+//! Connections are accepted on a plain `TcpListener` and handed to rustls before hyper sees them,
+//! so the same `Server` and services work with or without TLS. The certificate and key are read
+//! from PEM files (see `Config::tls`), and are reloaded whenever the files change or the server
+//! gets `SIGHUP`, so a renewed certificate is picked up without a restart.
+//!
+//! A second, plain HTTP listener can be run alongside, which only redirects to HTTPS.
+
+use std::{
+    fs,
+    io::{self, BufReader},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, RwLock},
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+use hyper::{Body, Request, Response, StatusCode, server::accept::{self, Accept}};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+use tokio_rustls::{
+    TlsAcceptor,
+    server::TlsStream,
+    rustls::{
+        ServerConfig,
+        crypto::{CryptoProvider, ring},
+        server::{ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+    },
+};
+use tracing::{debug, info, warn};
+
+/// Clients which haven't finished the handshake by then are dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often the certificate files are checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+// Certificates {{{
+
+/// The server's certificate, which can be swapped out while running
+#[derive(Debug)]
+pub struct CertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+    /// Modification times of the cert and key, as of the last load
+    modified: RwLock<(Option<SystemTime>, Option<SystemTime>)>,
+}
+
+impl CertResolver {
+    /// Loads the certificate chain and private key
+    pub fn new(cert_path: &Path, key_path: &Path) -> io::Result<Self> {
+        let modified = modified(cert_path, key_path);
+        let key = load_certified_key(cert_path, key_path)?;
+        Ok(Self {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            current: RwLock::new(Arc::new(key)),
+            modified: RwLock::new(modified),
+        })
+    }
+
+    /// Reads the files again, keeping the old certificate if they can't be loaded
+    pub fn reload(&self) -> io::Result<()> {
+        let modified = modified(&self.cert_path, &self.key_path);
+        let key = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.current.write().expect("certificate lock poisoned") = Arc::new(key);
+        *self.modified.write().expect("certificate lock poisoned") = modified;
+        info!(cert = ?self.cert_path, "certificate reloaded");
+        Ok(())
+    }
+
+    /// Reloads if either file was modified since the last load
+    pub fn reload_if_changed(&self) -> io::Result<bool> {
+        let modified = modified(&self.cert_path, &self.key_path);
+        if modified == *self.modified.read().expect("certificate lock poisoned") {
+            return Ok(false);
+        }
+        self.reload()?;
+        Ok(true)
+    }
+
+    /// The certificate being served
+    pub fn current(&self) -> Arc<CertifiedKey> {
+        self.current.read().expect("certificate lock poisoned").clone()
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current())
+    }
+}
+
+fn modified(cert_path: &Path, key_path: &Path) -> (Option<SystemTime>, Option<SystemTime>) {
+    let mtime = |p: &Path| fs::metadata(p).and_then(|m| m.modified()).ok();
+    (mtime(cert_path), mtime(key_path))
+}
+
+fn provider() -> CryptoProvider {
+    ring::default_provider()
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Reads a PEM certificate chain and private key, and checks that they belong together
+pub fn load_certified_key(cert_path: &Path, key_path: &Path) -> io::Result<CertifiedKey> {
+    let mut reader = BufReader::new(fs::File::open(cert_path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(invalid_data(format!("no certificates in {}", cert_path.display())));
+    }
+    let mut reader = BufReader::new(fs::File::open(key_path)?);
+    let key = rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| invalid_data(format!("no private key in {}", key_path.display())))?;
+    CertifiedKey::from_der(certs, key, &provider())
+        .map_err(|e| invalid_data(format!("{}: {e}", key_path.display())))
+}
+
+/// Builds the rustls config, advertising HTTP/2 and HTTP/1.1
+pub fn server_config(resolver: Arc<CertResolver>) -> io::Result<ServerConfig> {
+    let mut config = ServerConfig::builder_with_provider(Arc::new(provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// Reloads the certificate when its files change, or on `SIGHUP`
+pub async fn watch(resolver: Arc<CertResolver>) {
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+    #[cfg(unix)]
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .expect("failed to install the SIGHUP signal handler");
+    loop {
+        #[cfg(unix)]
+        let forced = tokio::select! {
+            _ = interval.tick() => false,
+            _ = hangup.recv() => true,
+        };
+        #[cfg(not(unix))]
+        let forced = { interval.tick().await; false };
+
+        let result = if forced { resolver.reload().map(|_| true) } else { resolver.reload_if_changed() };
+        if let Err(e) = result {
+            warn!("keeping the old certificate, since the new one could not be loaded: {e}");
+        }
+    }
+}
+
+// }}}
+
+// Connections {{{
+
+/// A TLS connection, with the address of the client
+pub struct TlsConn {
+    stream: TlsStream<TcpStream>,
+    remote: SocketAddr,
+}
+
+impl TlsConn {
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote
+    }
+}
+
+impl AsyncRead for TlsConn {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TlsConn {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(mut self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[io::IoSlice<'_>]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Accepts connections and completes their TLS handshakes, for `Server::builder`
+///
+/// Handshakes run in their own tasks, so a slow client doesn't hold up the others. Failed
+/// handshakes are only logged: an error from the `Accept` would stop the whole server.
+pub fn incoming(listener: TcpListener, acceptor: TlsAcceptor) -> impl Accept<Conn = TlsConn, Error = io::Error> {
+    let (tx, rx) = mpsc::channel::<TlsConn>(64);
+    tokio::spawn(async move {
+        while !tx.is_closed() {
+            let (tcp, remote) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    // NOTE: usually out of file descriptors, so give it a moment
+                    warn!("failed to accept connection: {e}");
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                },
+            };
+            let acceptor = acceptor.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(tcp)).await {
+                    Ok(Ok(stream)) => { let _ = tx.send(TlsConn { stream, remote }).await; },
+                    Ok(Err(e)) => debug!(%remote, "TLS handshake failed: {e}"),
+                    Err(_) => debug!(%remote, "TLS handshake timed out"),
+                }
+            });
+        }
+    });
+    let conns = futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|conn| (Ok(conn), rx))
+    });
+    accept::from_stream(conns)
+}
+
+// }}}
+
+// Redirect {{{
+
+/// Redirects a plain HTTP request to the same URL over HTTPS
+///
+/// The host is taken from the `Host` header, with its port replaced by `https_port` (left out if
+/// it is the default, 443).
+pub fn redirect(req: &Request<Body>, https_port: u16) -> Response<Body> {
+    let host = req.headers().get("host")
+        .and_then(|h| h.to_str().ok())
+        .or_else(|| req.uri().host());
+    let host = match host {
+        Some(host) => strip_port(host),
+        None => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from("Missing Host header"))
+                .unwrap();
+        },
+    };
+    let path = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let location = match https_port {
+        443 => format!("https://{host}{path}"),
+        port => format!("https://{host}:{port}{path}"),
+    };
+    Response::builder()
+        .status(StatusCode::PERMANENT_REDIRECT)
+        .header("Location", location)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// `example.com:80` to `example.com`, leaving IPv6 literals like `[::1]` whole
+fn strip_port(host: &str) -> &str {
+    match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    }
+}
+
+// }}}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a new self-signed certificate and key into `dir`
+    fn write_cert(dir: &Path) -> (PathBuf, PathBuf) {
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        fs::write(&cert, generated.cert.pem()).unwrap();
+        fs::write(&key, generated.signing_key.serialize_pem()).unwrap();
+        (cert, key)
+    }
+
+    #[test]
+    fn loads_and_reloads_certificates() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = write_cert(dir.path());
+        let resolver = CertResolver::new(&cert, &key).unwrap();
+        let first = resolver.current();
+        assert!(server_config(Arc::new(CertResolver::new(&cert, &key).unwrap())).is_ok());
+
+        write_cert(dir.path());
+        resolver.reload().unwrap();
+        assert_ne!(first.cert, resolver.current().cert);
+    }
+
+    #[test]
+    fn keeps_certificate_on_failed_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = write_cert(dir.path());
+        let resolver = CertResolver::new(&cert, &key).unwrap();
+        let first = resolver.current();
+
+        fs::write(&key, "not a key").unwrap();
+        assert!(resolver.reload().is_err());
+        assert_eq!(first.cert, resolver.current().cert);
+    }
+
+    #[test]
+    fn rejects_mismatched_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = write_cert(dir.path());
+        let other = dir.path().join("other");
+        fs::create_dir_all(&other).unwrap();
+        let (_, other_key) = write_cert(&other);
+        assert!(load_certified_key(&cert, &key).is_ok());
+        assert!(load_certified_key(&cert, &other_key).is_err());
+        assert!(load_certified_key(&dir.path().join("missing.pem"), &key).is_err());
+    }
+
+    #[test]
+    fn redirects_to_https() {
+        let req = |host: &str, uri: &str| {
+            Request::builder().uri(uri).header("host", host).body(Body::empty()).unwrap()
+        };
+        let location = |resp: Response<Body>| {
+            assert_eq!(resp.status(), StatusCode::PERMANENT_REDIRECT);
+            resp.headers()["location"].to_str().unwrap().to_string()
+        };
+        assert_eq!(location(redirect(&req("notes.local:7878", "/a/b.md?x=1"), 443)),
+                   "https://notes.local/a/b.md?x=1");
+        assert_eq!(location(redirect(&req("notes.local", "/"), 8443)),
+                   "https://notes.local:8443/");
+        assert_eq!(location(redirect(&req("[::1]:80", "/"), 443)), "https://[::1]/");
+    }
+}
@@ -1,63 +1,363 @@
 //! URI lookup library
 //!
 //! This library provides the `resolve()` function, which will map the URI from a get request to a
-//! local file.
+//! local file, without ever leaving the configured directories.
 //!
 
 use std::{
-    path::{PathBuf, Path}, ffi::OsStr,
+    path::{PathBuf, Path, Component}, ffi::OsStr,
 };
 
 use url_escape::decode as decode_url;
+use tracing::trace;
 
-use crate::config::Config;
+use crate::{
+    config::{Config, SymlinkPolicy},
+    error::Error,
+    index::TAGS_PREFIX,
+    highlight,
+    linkcheck,
+    cache,
+};
 
 #[derive(Debug)]
 pub enum Resolved {
     File(PathBuf),
     Markdown(PathBuf),
     Directory(PathBuf),
+    /// The list of all tags, or the page of one tag
+    Tags(Option<String>),
+    /// The stylesheet for highlighted code
+    HighlightCss,
+    /// The report of broken links
+    LinkReport,
+    /// Statistics of the render cache
+    CacheStats,
 }
 
-impl AsRef<Path> for Resolved {
-    fn as_ref(&self) -> &Path {
+impl Resolved {
+    /// Name of the variant, for logging
+    pub fn kind(&self) -> &'static str {
         match self {
-            Self::File(p) => p.as_ref(),
-            Self::Markdown(p) => p.as_ref(),
-            Self::Directory(p) => p.as_ref(),
+            Self::File(_) => "file",
+            Self::Markdown(_) => "markdown",
+            Self::Directory(_) => "directory",
+            Self::Tags(_) => "tags",
+            Self::HighlightCss => "highlight-css",
+            Self::LinkReport => "link-report",
+            Self::CacheStats => "cache-stats",
         }
     }
 }
 
-pub fn resolve(uri: &hyper::Uri, config: &Config) -> Option<Resolved> {
-    eprintln!("{:?}", uri.path());
-    let relpath = force_relative(&decode_url(uri.path()));
-    eprintln!("{:?}", relpath);
+/// Finds the file or directory for a request URI
+///
+/// The path is looked up in `config.rootdir`, and then `config.staticdir`. `..` segments are
+/// resolved before anything touches the filesystem, and paths which would climb out of the root
+/// are refused, as are symbolic links not allowed by `config.symlinks`. Paths denied by
+/// `config.filter` are reported as not found.
+///
+/// Paths under [TAGS_PREFIX] are reserved for the tag pages, [highlight::CSS_PATH] for the
+/// highlighting stylesheet, and (only with `config.diagnostics`) [linkcheck::REPORT_PATH] for the
+/// broken-link report and [cache::STATS_PATH] for the render cache's statistics. These are never
+/// looked up.
+pub fn resolve(uri: &hyper::Uri, config: &Config) -> Result<Resolved, Error> {
+    let decoded = decode_url(uri.path());
+    if let Some(tag) = reserved(&decoded, TAGS_PREFIX) {
+        return Ok(Resolved::Tags(tag));
+    }
+    if decoded == highlight::CSS_PATH {
+        return Ok(Resolved::HighlightCss);
+    }
+    if config.diagnostics && decoded == linkcheck::REPORT_PATH {
+        return Ok(Resolved::LinkReport);
+    }
+    if config.diagnostics && decoded == cache::STATS_PATH {
+        return Ok(Resolved::CacheStats);
+    }
+    let relpath = normalize(&decoded)?;
+    trace!(?relpath, "normalized request path");
+    if !config.filter.is_allowed(&relpath) {
+        return Err(Error::NotFound);
+    }
     let path = config.rootdir.join(&relpath);
 
     // TODO: support markdown files without an extension?
     //       what if there is both a file and directory: `things.md`, `things/stuff.md` ?
     if path.is_dir() {
-        return Some(Resolved::Directory(path));
+        check_symlinks(&config.rootdir, &relpath, config.symlinks)?;
+        return Ok(Resolved::Directory(path));
     } else if path.is_file() {
-        return if path.extension() == Some(&OsStr::new("md")) {
-            Some(Resolved::Markdown(path))
+        check_symlinks(&config.rootdir, &relpath, config.symlinks)?;
+        return if path.extension() == Some(OsStr::new("md")) {
+            Ok(Resolved::Markdown(path))
         } else {
-            Some(Resolved::File(path))
+            Ok(Resolved::File(path))
         }
     }
     // }}} 
     // Look in the staticdir
     let path = config.staticdir.join(&relpath);
     if path.is_file() {
-        return Some(Resolved::File(path));
+        check_symlinks(&config.staticdir, &relpath, config.symlinks)?;
+        return Ok(Resolved::File(path));
     }
     // Nothing found
-    None
+    Err(Error::NotFound)
 }
 
-fn force_relative(uri: &str) -> PathBuf {
-    assert!(uri.starts_with('/'), 
-            "The uri path for a request should always be absolute");
-    PathBuf::from(&uri[1..])
+/// Matches a decoded path against a reserved prefix like `/_tags/`, giving the rest of the path
+///
+/// Both `/_tags` and `/_tags/` give `Some(None)`.
+fn reserved(path: &str, prefix: &str) -> Option<Option<String>> {
+    let rest = match path.strip_prefix(prefix) {
+        Some(rest) => rest,
+        None if path == prefix.trim_end_matches('/') => "",
+        None => return None,
+    };
+    let rest = rest.trim_matches('/');
+    Some(if rest.is_empty() { None } else { Some(rest.to_string()) })
+}
+
+/// Turns a decoded URI path into a relative path, without any `.` or `..` components
+///
+/// NOTE: the path must be decoded first, so that encoded slashes and dots (`%2F`, `%2e`)
+/// are normalised as well.
+fn normalize(uri: &str) -> Result<PathBuf, Error> {
+    // e.g. the `*` of `OPTIONS *`
+    let uri = uri.strip_prefix('/').ok_or(Error::NotFound)?;
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in uri.split('/') {
+        match segment {
+            "" | "." => continue,
+            ".." => {
+                if segments.pop().is_none() {
+                    return Err(Error::Forbidden);
+                }
+            },
+            s if s.contains('\0') || s.contains('\\') => return Err(Error::Forbidden),
+            s => segments.push(s),
+        }
+    }
+    let relpath: PathBuf = segments.iter().collect();
+    // Catch anything the platform treats specially, like drive prefixes on windows
+    if !relpath.components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err(Error::Forbidden);
+    }
+    Ok(relpath)
+}
+
+/// Enforces the symlink policy for `relpath` inside `base`, which must exist
+fn check_symlinks(base: &Path, relpath: &Path, policy: SymlinkPolicy) -> Result<(), Error> {
+    match policy {
+        SymlinkPolicy::All => Ok(()),
+        SymlinkPolicy::Deny => {
+            let mut path = base.to_path_buf();
+            for component in relpath.components() {
+                path.push(component);
+                if path.symlink_metadata()?.file_type().is_symlink() {
+                    return Err(Error::Forbidden);
+                }
+            }
+            Ok(())
+        },
+        SymlinkPolicy::WithinRoot => {
+            let root = base.canonicalize()?;
+            let target = base.join(relpath).canonicalize()?;
+            if target.starts_with(root) {
+                Ok(())
+            } else {
+                Err(Error::Forbidden)
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    #[cfg(unix)]
+    use std::os::unix::fs::symlink;
+
+    /// Builds a web root next to a "secret" directory that must never be served
+    ///
+    /// ```text
+    /// <tmp>/root/note.md
+    /// <tmp>/root/dir/inner.txt
+    /// <tmp>/root/link-in -> root/note.md     (unix only)
+    /// <tmp>/root/link-out -> secret/passwd   (unix only)
+    /// <tmp>/secret/passwd
+    /// <tmp>/static/style.css
+    /// ```
+    fn fixture() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path();
+        fs::create_dir_all(base.join("root/dir")).unwrap();
+        fs::create_dir_all(base.join("secret")).unwrap();
+        fs::create_dir_all(base.join("static")).unwrap();
+        fs::write(base.join("root/note.md"), "# note").unwrap();
+        fs::write(base.join("root/dir/inner.txt"), "inner").unwrap();
+        fs::write(base.join("secret/passwd"), "secret").unwrap();
+        fs::write(base.join("static/style.css"), "body {}").unwrap();
+        #[cfg(unix)]
+        {
+            symlink(base.join("root/note.md"), base.join("root/link-in")).unwrap();
+            symlink(base.join("secret/passwd"), base.join("root/link-out")).unwrap();
+        }
+        dir
+    }
+
+    fn config(base: &Path, symlinks: SymlinkPolicy) -> Config {
+        let mut config = Config::builder();
+        config.set_root(&base.join("root"));
+        config.set_static(&base.join("static"));
+        config.set_symlinks(symlinks);
+        config.build()
+    }
+
+    fn lookup(uri: &str, config: &Config) -> Result<Resolved, Error> {
+        resolve(&uri.parse::<hyper::Uri>().unwrap(), config)
+    }
+
+    #[test]
+    fn resolves_normal_paths() {
+        let dir = fixture();
+        let base = dir.path();
+        let config = config(base, SymlinkPolicy::WithinRoot);
+        assert!(matches!(lookup("/note.md", &config), Ok(Resolved::Markdown(_))));
+        assert!(matches!(lookup("/dir/", &config), Ok(Resolved::Directory(_))));
+        assert!(matches!(lookup("/dir/inner.txt", &config), Ok(Resolved::File(_))));
+        assert!(matches!(lookup("/style.css", &config), Ok(Resolved::File(_))));
+        assert!(matches!(lookup("/missing.md", &config), Err(Error::NotFound)));
+    }
+
+    #[test]
+    fn reserves_tag_paths() {
+        let config = Config::default();
+        let tag = |uri: &str| match lookup(uri, &config) {
+            Ok(Resolved::Tags(tag)) => tag,
+            other => panic!("{uri} resolved to {other:?}"),
+        };
+        assert_eq!(tag("/_tags"), None);
+        assert_eq!(tag("/_tags/"), None);
+        assert_eq!(tag("/_tags/project/md-server/"), Some("project/md-server".to_string()));
+        assert_eq!(tag("/_tags/two%20words"), Some("two words".to_string()));
+        assert!(!matches!(lookup("/_tagsfoo", &config), Ok(Resolved::Tags(_))));
+    }
+
+    #[test]
+    fn reserves_diagnostics_only_when_enabled() {
+        let dir = fixture();
+        let base = dir.path();
+        let mut config = config(base, SymlinkPolicy::WithinRoot);
+        assert!(matches!(lookup("/_links", &config), Err(Error::NotFound)));
+        assert!(matches!(lookup("/_cache", &config), Err(Error::NotFound)));
+        config.diagnostics = true;
+        assert!(matches!(lookup("/_links", &config), Ok(Resolved::LinkReport)));
+        assert!(matches!(lookup("/_cache", &config), Ok(Resolved::CacheStats)));
+    }
+
+    #[test]
+    fn normalises_dot_segments_inside_root() {
+        let dir = fixture();
+        let base = dir.path();
+        let config = config(base, SymlinkPolicy::WithinRoot);
+        assert!(matches!(lookup("/dir/../note.md", &config), Ok(Resolved::Markdown(_))));
+        assert!(matches!(lookup("/./dir/./inner.txt", &config), Ok(Resolved::File(_))));
+        assert!(matches!(lookup("//dir//inner.txt", &config), Ok(Resolved::File(_))));
+    }
+
+    #[test]
+    fn refuses_traversal() {
+        let dir = fixture();
+        let base = dir.path();
+        let config = config(base, SymlinkPolicy::All);
+        let hostile = [
+            "/../secret/passwd",
+            "/dir/../../secret/passwd",
+            "/%2e%2e/secret/passwd",
+            "/%2E%2E%2Fsecret%2Fpasswd",
+            "/dir/..%2f..%2fsecret/passwd",
+            "/..%5c..%5csecret%5cpasswd",
+            "/note.md%00.txt",
+        ];
+        for uri in hostile {
+            assert!(matches!(lookup(uri, &config), Err(Error::Forbidden)), "{uri} was not refused");
+        }
+    }
+
+    #[test]
+    fn absolute_looking_paths_stay_in_root() {
+        let dir = fixture();
+        let base = dir.path();
+        let config = config(base, SymlinkPolicy::All);
+        let secret = base.join("secret/passwd");
+        let uri = format!("/{}", secret.to_string_lossy());
+        assert!(matches!(lookup(&uri, &config), Err(Error::NotFound)));
+        let uri = format!("/%2F{}", &secret.to_string_lossy()[1..]);
+        assert!(matches!(lookup(&uri, &config), Err(Error::NotFound)));
+    }
+
+    #[test]
+    fn hides_denied_paths() {
+        let dir = fixture();
+        let base = dir.path();
+        fs::create_dir_all(base.join("root/.git")).unwrap();
+        fs::write(base.join("root/.git/config"), "[core]").unwrap();
+        fs::write(base.join("root/.env"), "TOKEN=secret").unwrap();
+        let config = config(base, SymlinkPolicy::WithinRoot);
+        assert!(matches!(lookup("/.git/config", &config), Err(Error::NotFound)));
+        assert!(matches!(lookup("/.git/", &config), Err(Error::NotFound)));
+        assert!(matches!(lookup("/.env", &config), Err(Error::NotFound)));
+        assert!(matches!(lookup("/dir/../.env", &config), Err(Error::NotFound)));
+        assert!(matches!(lookup("/%2eenv", &config), Err(Error::NotFound)));
+    }
+
+    #[test]
+    fn filters_relative_roots_once() {
+        // NOTE: a root relative to the working directory, holding a directory of the same name
+        let dir = tempfile::Builder::new().prefix("notes").tempdir_in(".").unwrap();
+        let root = Path::new(dir.path().file_name().unwrap());
+        let name = root.to_string_lossy();
+        fs::create_dir_all(root.join(root)).unwrap();
+        fs::write(root.join(root).join("x.md"), "# x").unwrap();
+        fs::write(root.join("x.md"), "# x").unwrap();
+        let mut config = Config::builder();
+        config.set_root(root);
+        config.set_deny(&[format!("{name}/*")]);
+        let config = config.build();
+        assert!(matches!(lookup(&format!("/{name}/x.md"), &config), Err(Error::NotFound)));
+        assert!(matches!(lookup("/x.md", &config), Ok(Resolved::Markdown(_))));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn symlink_policy_deny() {
+        let dir = fixture();
+        let base = dir.path();
+        let config = config(base, SymlinkPolicy::Deny);
+        assert!(matches!(lookup("/link-in", &config), Err(Error::Forbidden)));
+        assert!(matches!(lookup("/link-out", &config), Err(Error::Forbidden)));
+        assert!(matches!(lookup("/note.md", &config), Ok(Resolved::Markdown(_))));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn symlink_policy_within_root() {
+        let dir = fixture();
+        let base = dir.path();
+        let config = config(base, SymlinkPolicy::WithinRoot);
+        assert!(matches!(lookup("/link-in", &config), Ok(Resolved::File(_))));
+        assert!(matches!(lookup("/link-out", &config), Err(Error::Forbidden)));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn symlink_policy_all() {
+        let dir = fixture();
+        let base = dir.path();
+        let config = config(base, SymlinkPolicy::All);
+        assert!(matches!(lookup("/link-out", &config), Ok(Resolved::File(_))));
+    }
 }
@@ -0,0 +1,239 @@
+//! Wiki-style links between notes
+//!
+//! `[[note name]]`, `[[note name|label]]` and `[[note name#heading]]` link to a file by its name,
+//! rather than its path. Names are matched case-insensitively, and `.md` can be left off. A name
+//! with a `/` in it, like `[[projects/plan]]`, matches the end of the file's path instead.
+//!
+//! When more than one file has the name, the link goes to the one closest to the note it's in:
+//! the one sharing the most directories with it, then the shallowest one.
+//!
+//! A `#heading` is turned into an id the way the heading's own id is made, without reading the
+//! note it's in, so it links to the first heading with that text (repeats are numbered, like
+//! `notes-1`, and can be linked to by that id with a markdown link).
+
+use std::{collections::HashMap, sync::Arc};
+
+use pulldown_cmark::{Event, Tag, LinkType, CowStr, escape::escape_html};
+
+use crate::{context::Directory, markdown::slug};
+
+/// Resolves the wiki links of one note
+///
+/// The names are shared between clones, so the links of the whole tree can be made once and
+/// cloned for each note.
+#[derive(Debug, Clone, Default)]
+pub struct WikiLinks {
+    /// URL paths of files by their lowercase name, with and without `.md`
+    names: Arc<HashMap<String, Vec<String>>>,
+    /// URL path of the note being rendered
+    from: String,
+}
+
+impl WikiLinks {
+    /// Links from the note at the URL path `from` to the files in `tree`
+    pub fn new(tree: &Directory, from: &str) -> Self {
+        Self::from_paths(tree.all_files().into_iter().map(|f| f.path()), from)
+    }
+
+    fn from_paths<'p>(paths: impl Iterator<Item = &'p str>, from: &str) -> Self {
+        let mut names: HashMap<String, Vec<String>> = HashMap::new();
+        for path in paths {
+            let name = path.rsplit('/').next().unwrap_or(path).to_lowercase();
+            if let Some(stem) = name.strip_suffix(".md") {
+                names.entry(stem.to_string()).or_default().push(path.to_string());
+            }
+            names.entry(name).or_default().push(path.to_string());
+        }
+        Self { names: Arc::new(names), from: from.to_string() }
+    }
+
+    /// Resolve the links of another note, with the same files
+    pub fn set_from(&mut self, from: &str) {
+        self.from = from.to_string();
+    }
+
+    /// The URL that a link target like `note#heading` points to, if the note exists
+    pub fn resolve(&self, target: &str) -> Option<String> {
+        let (name, heading) = split_heading(target);
+        let fragment = heading.map(|h| format!("#{}", slug(h))).unwrap_or_default();
+        if name.is_empty() {
+            // A heading in this note
+            return heading.map(|_| fragment);
+        }
+        let path = self.find(name)?;
+        Some(format!("{}{fragment}", url_escape::encode_path(path)))
+    }
+
+    /// URL path of the file that a link target points to, leaving out any heading
+    pub fn path(&self, target: &str) -> Option<&str> {
+        match split_heading(target) {
+            ("", _) => None,
+            (name, _) => self.find(name),
+        }
+    }
+
+    /// URL path of the closest file with the name
+    fn find(&self, name: &str) -> Option<&str> {
+        let name = name.to_lowercase();
+        let (dirs, base) = match name.rsplit_once('/') {
+            Some((dirs, base)) => (Some(dirs.trim_matches('/')), base),
+            None => (None, name.as_str()),
+        };
+        let candidates = self.names.get(base)?.iter()
+            .filter(|path| match dirs {
+                Some(dirs) => parent(&path.to_lowercase()).ends_with(&format!("/{dirs}")),
+                None => true,
+            });
+        candidates
+            .min_by_key(|path| {
+                let shared = common_dirs(parent(&self.from), parent(path));
+                (std::cmp::Reverse(shared), path.matches('/').count(), path.as_str())
+            })
+            .map(String::as_str)
+    }
+
+    /// Replaces `[[links]]` in text with links, or with a "missing" marker when nothing matches
+    pub fn events<'a>(&self, events: impl Iterator<Item = Event<'a>>) -> Vec<Event<'a>> {
+        let mut out = Vec::new();
+        // Text since the last other event, since brackets come as separate text events
+        let mut text = String::new();
+        // Inside code blocks, links or images, where links can't go
+        let mut depth = 0;
+        for event in events {
+            match event {
+                Event::Text(t) if depth == 0 => {
+                    text.push_str(&t);
+                    continue;
+                },
+                Event::Start(Tag::CodeBlock(_) | Tag::Link(..) | Tag::Image(..)) => depth += 1,
+                Event::End(Tag::CodeBlock(_) | Tag::Link(..) | Tag::Image(..)) => depth -= 1,
+                _ => (),
+            }
+            self.flush(&mut text, &mut out);
+            out.push(event);
+        }
+        self.flush(&mut text, &mut out);
+        out
+    }
+
+    fn flush<'a>(&self, text: &mut String, out: &mut Vec<Event<'a>>) {
+        let mut rest = text.as_str();
+        while let Some((before, inner, after)) = find_link(rest) {
+            if !before.is_empty() {
+                out.push(Event::Text(CowStr::from(before.to_string())));
+            }
+            let (target, label) = match inner.split_once('|') {
+                Some((target, label)) => (target, label.trim()),
+                None => (inner, inner.trim()),
+            };
+            match self.resolve(target) {
+                Some(url) => out.extend([
+                    Event::Start(Tag::Link(LinkType::Inline, CowStr::from(url.clone()), CowStr::from(""))),
+                    Event::Text(CowStr::from(label.to_string())),
+                    Event::End(Tag::Link(LinkType::Inline, CowStr::from(url), CowStr::from(""))),
+                ]),
+                None => out.push(Event::Html(CowStr::from(missing(target.trim(), label)))),
+            }
+            rest = after;
+        }
+        if !rest.is_empty() {
+            out.push(Event::Text(CowStr::from(rest.to_string())));
+        }
+        text.clear();
+    }
+}
+
+/// `note#heading` to `note` and `heading`
+fn split_heading(target: &str) -> (&str, Option<&str>) {
+    match target.split_once('#') {
+        Some((name, heading)) => (name.trim(), Some(heading.trim())),
+        None => (target.trim(), None),
+    }
+}
+
+/// Splits text around its first `[[link]]`, giving the text before, inside and after it
+pub(crate) fn find_link(text: &str) -> Option<(&str, &str, &str)> {
+    let mut from = 0;
+    while let Some(start) = text[from..].find("[[").map(|i| from + i) {
+        let inner = &text[start + 2..];
+        let end = inner.find("]]")?;
+        let link = &inner[..end];
+        if !link.trim().is_empty() && !link.contains(['[', ']', '\n']) {
+            return Some((&text[..start], link, &inner[end + 2..]));
+        }
+        from = start + 1;
+    }
+    None
+}
+
+/// The html for a link to a file that doesn't exist
+fn missing(target: &str, label: &str) -> String {
+    let mut html = String::from("<span class=\"wikilink-missing\" title=\"No note named ");
+    escape_html(&mut html, target).expect("writing to a string can't fail");
+    html.push_str("\">");
+    escape_html(&mut html, label).expect("writing to a string can't fail");
+    html.push_str("</span>");
+    html
+}
+
+/// Directory part of a URL path: `/a/b` for `/a/b/c.md`
+fn parent(path: &str) -> &str {
+    path.rsplit_once('/').map(|(dir, _)| dir).unwrap_or("")
+}
+
+/// Number of leading directories that two directory paths share
+fn common_dirs(a: &str, b: &str) -> usize {
+    a.split('/').zip(b.split('/'))
+        .skip(1)
+        .take_while(|(a, b)| a == b)
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pulldown_cmark::{Parser, html};
+
+    fn links(paths: &[&str], from: &str) -> WikiLinks {
+        WikiLinks::from_paths(paths.iter().copied(), from)
+    }
+
+    fn render(source: &str, links: &WikiLinks) -> String {
+        let mut out = String::new();
+        html::push_html(&mut out, links.events(Parser::new(source)).into_iter());
+        out
+    }
+
+    #[test]
+    fn resolves_by_name() {
+        let links = links(&["/notes/Usage.md", "/img/diagram.png"], "/index.md");
+        assert_eq!(links.resolve("usage").as_deref(), Some("/notes/Usage.md"));
+        assert_eq!(links.resolve("Usage.md").as_deref(), Some("/notes/Usage.md"));
+        assert_eq!(links.resolve("notes/usage").as_deref(), Some("/notes/Usage.md"));
+        assert_eq!(links.resolve("usage#Running it").as_deref(), Some("/notes/Usage.md#running-it"));
+        assert_eq!(links.resolve("#Here").as_deref(), Some("#here"));
+        assert_eq!(links.resolve("diagram.png").as_deref(), Some("/img/diagram.png"));
+        assert_eq!(links.resolve("other/usage"), None);
+        assert_eq!(links.resolve("missing"), None);
+    }
+
+    #[test]
+    fn prefers_closest_note() {
+        let paths = ["/todo.md", "/work/todo.md", "/work/project/todo.md", "/home/todo.md"];
+        assert_eq!(links(&paths, "/work/project/plan.md").resolve("todo").as_deref(), Some("/work/project/todo.md"));
+        assert_eq!(links(&paths, "/work/other/plan.md").resolve("todo").as_deref(), Some("/work/todo.md"));
+        assert_eq!(links(&paths, "/misc/plan.md").resolve("todo").as_deref(), Some("/todo.md"));
+        assert_eq!(links(&paths[1..], "/plan.md").resolve("todo").as_deref(), Some("/home/todo.md"));
+    }
+
+    #[test]
+    fn renders_links() {
+        let links = links(&["/notes/my note.md"], "/index.md");
+        assert_eq!(render("See [[My Note|this]] and [[nothing]].", &links), concat!(
+            "<p>See <a href=\"/notes/my%20note.md\">this</a> and ",
+            "<span class=\"wikilink-missing\" title=\"No note named nothing\">nothing</span>.</p>\n",
+        ));
+        assert_eq!(render("`[[my note]]` [[ ]]", &links), "<p><code>[[my note]]</code> [[ ]]</p>\n");
+        assert_eq!(render("```\n[[my note]]\n```", &links), "<pre><code>[[my note]]\n</code></pre>\n");
+    }
+}